@@ -0,0 +1,42 @@
+// Cost of linking the blocks of fresh page extensions: in address order by
+// default, in random order with the `random-free-list` feature. Only that
+// feature differs between the two runs (no free list encoding of `secure`):
+//   cargo bench --bench free_list
+//   cargo bench --bench free_list --features random-free-list
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+#[global_allocator]
+static GLOBAL: mimalloc_rs::Mimalloc = mimalloc_rs::Mimalloc;
+
+const ROUNDS: usize = 50;
+const BLOCKS: usize = 100_000;
+
+// Allocate enough blocks that most of them come from newly extended pages;
+// only the allocations are timed
+fn round(size: usize, blocks: &mut Vec<Box<[u8]>>) -> Duration {
+    let start = Instant::now();
+    for _ in 0..BLOCKS {
+        blocks.push(vec![0u8; size].into_boxed_slice());
+    }
+    let elapsed = start.elapsed();
+    black_box(&blocks);
+
+    // free them all so the next round starts with empty pages again
+    blocks.clear();
+    unsafe { mimalloc_rs::heap_collect_abandon(mimalloc_rs::heap_get_default()); }
+    elapsed
+}
+
+fn main() {
+    let order = if cfg!(feature = "random-free-list") { "random" } else { "sequential" };
+    let mut blocks: Vec<Box<[u8]>> = Vec::with_capacity(BLOCKS);
+    for &size in &[16usize, 64, 256, 1024] {
+        round(size, &mut blocks);  // warm up
+        let total: Duration = (0..ROUNDS).map(|_| round(size, &mut blocks)).sum();
+        let ns = total.as_nanos() / (ROUNDS * BLOCKS) as u128;
+        println!("{:>10} free lists, {:>5}b blocks: {:>4} ns per allocation", order, size, ns);
+    }
+}
@@ -0,0 +1,577 @@
+use crate::{init::*, internal::*, options::*, os::*, page::*, segment::*, stats::*, types::*};
+
+use core::{
+    ptr::{addr_of_mut, null_mut},
+    sync::atomic::Ordering,
+};
+
+/* -----------------------------------------------------------
+  Debug fill and padding
+  With the `debug` feature new blocks are filled with `MI_DEBUG_UNINIT`
+  and freed blocks with `MI_DEBUG_FREED`. Every block ends in a `Padding`
+  that records how far the requested size is from the padding; the bytes
+  in between hold `MI_DEBUG_PADDING`, and both are checked on free.
+  The padding is part of `Page::block_size`.
+----------------------------------------------------------- */
+
+#[cfg(feature = "debug")]
+const MI_DEBUG_UNINIT: u8 = 0xD0;
+#[cfg(feature = "debug")]
+pub const MI_DEBUG_FREED: u8 = 0xDF;
+#[cfg(feature = "debug")]
+const MI_DEBUG_PADDING: u8 = 0xDE;
+#[cfg(feature = "debug")]
+const MI_PADDING_CANARY: usize = 0xDEADBEEF;
+
+#[cfg(feature = "debug")]
+struct Padding {
+    canary: usize,  // depends on the block address so a copied padding does not pass
+    delta:  usize,  // bytes between the end of the requested size and the padding
+}
+
+#[cfg(feature = "debug")]
+pub const MI_PADDING_SIZE: usize = core::mem::size_of::<Padding>();
+#[cfg(not(feature = "debug"))]
+pub const MI_PADDING_SIZE: usize = 0;
+
+#[cfg(feature = "debug")]
+unsafe fn block_padding(page: *const Page, block: *const Block) -> *mut Padding {
+    (block as *mut u8).add((*page).block_size - MI_PADDING_SIZE) as *mut Padding
+}
+
+// Record that `size` bytes of `block` are in use
+#[inline]
+unsafe fn block_set_padding(page: *const Page, block: *mut Block, size: usize) {
+    #[cfg(feature = "debug")]
+    {
+        let padding: *mut Padding = block_padding(page, block);
+        let delta: usize = padding as usize - block as usize - size;
+        (*padding).canary = block as usize ^ MI_PADDING_CANARY;
+        (*padding).delta = delta;
+        core::ptr::write_bytes((block as *mut u8).add(size), MI_DEBUG_PADDING, delta);
+    }
+    #[cfg(not(feature = "debug"))]
+    {
+        let _ = (page, block, size);
+    }
+}
+
+// Report a write past the requested size of a block that is freed
+#[cfg(feature = "debug")]
+unsafe fn check_padding(page: *const Page, block: *const Block) {
+    let padding: *const Padding = block_padding(page, block);
+    let delta: usize = (*padding).delta;
+    let mut ok: bool = (*padding).canary == (block as usize ^ MI_PADDING_CANARY) &&
+                       delta <= (*page).block_size - MI_PADDING_SIZE;
+    if ok {
+        let fill: *const u8 = (padding as *const u8).sub(delta);
+        ok = (0..delta).all(|i| *fill.add(i) == MI_DEBUG_PADDING);
+    }
+    if !ok {
+        _error_message(libc::EFAULT, format_args!("buffer overflow in heap block {:p} of page {:p} with block size {}", block, page, (*page).block_size));
+    }
+}
+
+/* -----------------------------------------------------------
+  Allocation
+----------------------------------------------------------- */
+
+// Fast allocation in a page: just pop from the free list.
+// `size` includes the padding.
+pub unsafe fn _page_malloc(heap: *mut Heap, page: *mut Page, size: usize) -> *mut u8 {
+    let block: *mut Block = (*page).free;
+    if block.is_null() {
+        return _malloc_generic(heap, size);  // slow path
+    }
+    // pop from the free list
+    (*page).free = block_next(page, block);
+    (*page).used += 1;
+    #[cfg(feature = "debug")]
+    {
+        core::ptr::write_bytes(block as *mut u8, MI_DEBUG_UNINIT, (*page).block_size);
+    }
+    block_set_padding(page, block, size - MI_PADDING_SIZE);
+    block as *mut u8
+}
+
+pub unsafe fn heap_malloc(heap: *mut Heap, size: usize) -> *mut u8 {
+    if guarded_sample(heap, size) {
+        let p: *mut u8 = guarded_malloc(heap, size);
+        if !p.is_null() { return p; }
+    }
+    let padded: usize = size + MI_PADDING_SIZE;
+    if padded <= MI_SMALL_SIZE_MAX {
+        _page_malloc(heap, heap_get_free_small_page(heap, padded), padded)
+    }
+    else {
+        _malloc_generic(heap, padded)
+    }
+}
+
+pub unsafe fn _malloc(size: usize) -> *mut u8 {
+    heap_malloc(get_default_heap(), size)
+}
+
+/* -----------------------------------------------------------
+  Reallocation
+----------------------------------------------------------- */
+
+// A page moved to a new address; fix up the links in the heap page queues.
+unsafe fn heap_page_moved(heap: *mut Heap, old: *mut Page, page: *mut Page) {
+    if !(*page).prev.is_null() { (*(*page).prev).next = page; }
+    if !(*page).next.is_null() { (*(*page).next).prev = page; }
+    for pq in (*heap).pages.iter_mut() {
+        if pq.first == old { pq.first = page; }
+        if pq.last == old { pq.last = page; }
+    }
+}
+
+// Resize a huge block by remapping its segment instead of copying.
+// Returns null if `p` is not a huge block owned by the current thread or
+// if the remap failed; `p` is still valid in that case.
+pub unsafe fn _realloc_huge(p: *mut u8, newsize: usize) -> *mut u8 {
+    if p.is_null() || newsize + MI_PADDING_SIZE <= MI_LARGE_SIZE_MAX { return null_mut(); }
+    let segment: *mut Segment = ptr_segment(p);
+    if (*segment).page_kind != PAGE_HUGE || (*segment).is_guarded || (*segment).thread_id != thread_id() { return null_mut(); }
+    let page: *mut Page = segment_page_of(segment, p);
+    // only a block at the start of the page keeps its alignment when moved
+    if p != page_start(segment, page, null_mut()) { return null_mut(); }
+
+    let heap: *mut Heap = (*page).heap;
+    let newpage: *mut Page = _segment_huge_page_realloc(page, newsize + MI_PADDING_SIZE, &mut (*(*heap).tld).segments);
+    if newpage.is_null() { return null_mut(); }
+    if newpage != page { heap_page_moved(heap, page, newpage); }
+    let block: *mut u8 = page_start(page_segment(newpage), newpage, null_mut());
+    block_set_padding(newpage, block as *mut Block, newsize);
+    block
+}
+
+/// Try to grow (or shrink) the allocation at `p` to `newsize` bytes without moving it.
+/// Returns `true` if the block now holds at least `newsize` bytes.
+pub unsafe fn expand(p: *mut u8, newsize: usize) -> bool {
+    if p.is_null() { return false; }
+    let segment: *mut Segment = ptr_segment(p);
+    let page: *mut Page = segment_page_of(segment, p);
+    // `p` may point inside its block for aligned allocations
+    let offset: usize = (p as usize - page_start(segment, page, null_mut()) as usize) % (*page).block_size;
+    let block: *mut Block = p.sub(offset) as *mut Block;
+    if (*segment).is_guarded {
+        // a guarded block ends at its guard page and has no padding
+        return newsize <= (*page).block_size - os_page_size() - offset;
+    }
+    if newsize <= (*page).block_size - MI_PADDING_SIZE - offset {
+        block_set_padding(page, block, offset + newsize);
+        return true;
+    }
+
+    // a huge page can grow into the virtual range right behind its segment
+    if (*segment).page_kind != PAGE_HUGE || (*segment).thread_id != thread_id() { return false; }
+    if !_segment_huge_page_expand(page, offset + newsize + MI_PADDING_SIZE, &mut (*(*(*page).heap).tld).segments) {
+        return false;
+    }
+    block_set_padding(page, block, offset + newsize);
+    true
+}
+
+/* -----------------------------------------------------------
+  Free
+----------------------------------------------------------- */
+
+#[cfg(any(feature = "secure", feature = "debug", debug_assertions))]
+unsafe fn list_contains(page: *const Page, mut list: *mut Block, elem: *const Block) -> bool {
+    while !list.is_null() {
+        if list as *const Block == elem { return true; }
+        list = block_next(page, list);
+    }
+    false
+}
+
+#[cfg(any(feature = "secure", feature = "debug", debug_assertions))]
+unsafe fn check_is_double_freex(page: *const Page, block: *const Block) -> bool {
+    // the block may be in any of the free lists of its page
+    if list_contains(page, (*page).free, block) ||
+       list_contains(page, (*page).local_free, block) ||
+       list_contains(page, (*page).thread_free.head(), block) ||
+       quarantine_contains(page, block)
+    {
+        _error_message(libc::EFAULT, format_args!("double free detected of block {:p} with size {}", block, (*page).block_size));
+        return true;
+    }
+    false
+}
+
+// Only scan the free lists if the block looks like a free list entry:
+// its (decoded) next pointer is null or points into the same page.
+// Quarantined blocks link to blocks of other pages, so those are always scanned.
+#[cfg(any(feature = "secure", feature = "debug", debug_assertions))]
+unsafe fn check_is_double_free(page: *const Page, block: *const Block) -> bool {
+    let n: *mut Block = block_nextx(page, block);
+    if (n as usize & (MI_INTPTR_SIZE - 1) == 0 && (n.is_null() || is_in_same_page(block, n))) ||
+       page_quarantined(page) > 0
+    {
+        check_is_double_freex(page, block)
+    }
+    else {
+        false
+    }
+}
+
+// Free a block of a page owned by another thread (or abandoned)
+unsafe fn free_block_mt(page: *mut Page, block: *mut Block) {
+    // the bottom 2 bits of the list are not part of the pointer and are kept;
+    // the owner collects the list when it searches the page for free blocks
+    let mut tfree: usize = (*page).thread_free.value.load(Ordering::Relaxed);
+    loop {
+        block_set_next(page, block, (tfree & !3) as *mut Block);
+        let tfreex: usize = block as usize | (tfree & 3);
+        match (*page).thread_free.value.compare_exchange_weak(tfree, tfreex, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(current) => tfree = current,
+        }
+    }
+    (*page).thread_freed.fetch_add(1, Ordering::Relaxed);
+}
+
+// Free a block of a page owned by this thread
+pub unsafe fn _free_block_local(page: *mut Page, block: *mut Block) {
+    block_set_next(page, block, (*page).local_free);
+    (*page).local_free = block;
+    (*page).used -= 1;
+    if page_all_free(page) { _page_retire(page); }
+}
+
+// Free a block. Pointers that we did not hand out, or that were already
+// freed, are reported through the error handler and otherwise ignored.
+pub unsafe fn _free(p: *mut u8) {
+    if p.is_null() { return; }
+    if p as usize & (MI_INTPTR_SIZE - 1) != 0 {
+        _error_message(libc::EFAULT, format_args!("trying to free an invalid (unaligned) pointer: {:p}", p));
+        return;
+    }
+    // check the segment map before reading the segment: `p` may not belong to us at all
+    if !is_in_heap_region(p) {
+        _error_message(libc::EFAULT, format_args!("trying to free a pointer that does not point to a valid heap space: {:p}", p));
+        return;
+    }
+    let segment: *mut Segment = ptr_segment(p);
+    if segment.is_null() || ptr_cookie(segment as *const u8) != (*segment).cookie {
+        _error_message(libc::EFAULT, format_args!("trying to free a pointer that does not point to a valid heap space: {:p}", p));
+        return;
+    }
+    let page: *mut Page = segment_page_of(segment, p);
+    let start: *mut u8 = page_start(segment, page, null_mut());
+    let bsize: usize = (*page).block_size;
+    if !(*page).segment_in_use || p < start || p >= start.add((*page).capacity as usize * bsize) {
+        _error_message(libc::EFAULT, format_args!("trying to free a pointer that is not in an allocated page: {:p}", p));
+        return;
+    }
+
+    // aligned allocations may point inside their block
+    let offset: usize = (p as usize - start as usize) % bsize;
+    let block: *mut Block = if offset == 0 {
+        p as *mut Block
+    }
+    else if (*page).flags.has_aligned() {
+        p.sub(offset) as *mut Block
+    }
+    else {
+        _error_message(libc::EFAULT, format_args!("trying to free a pointer that does not point to the start of a block: {:p} (block size {})", p, bsize));
+        return;
+    };
+
+    if (*segment).is_guarded {
+        guarded_free(page, block);
+        return;
+    }
+
+    #[cfg(any(feature = "secure", feature = "debug", debug_assertions))]
+    {
+        if check_is_double_free(page, block) { return; }
+    }
+    #[cfg(feature = "debug")]
+    {
+        check_padding(page, block);
+        core::ptr::write_bytes(block as *mut u8, MI_DEBUG_FREED, bsize);
+    }
+
+    if (*segment).thread_id == thread_id() {
+        // local free
+        #[cfg(feature = "secure")]
+        {
+            if quarantine_push((*page).heap, page, block) { return; }
+        }
+        _free_block_local(page, block);
+    }
+    else {
+        free_block_mt(page, block);
+    }
+}
+
+/* -----------------------------------------------------------
+  Quarantine
+  With the `secure` feature and `option_quarantine` set, locally freed
+  blocks wait in a per-heap FIFO before they go back to the `local_free`
+  list of their page, so a use-after-free cannot count on its block being
+  handed out again right away. The FIFO is bounded by `option_quarantine`
+  blocks and `option_quarantine_size` bytes. With the `debug` feature a
+  released block is checked to still hold the `MI_DEBUG_FREED` fill.
+  Quarantined blocks stay counted in `Page::used`; once a page has no
+  other blocks in use its blocks are released so the page can retire.
+----------------------------------------------------------- */
+
+#[cfg(any(feature = "secure", feature = "debug", debug_assertions))]
+#[inline]
+unsafe fn page_quarantined(page: *const Page) -> u16 {
+    #[cfg(feature = "secure")]
+    { (*page).quarantined }
+    #[cfg(not(feature = "secure"))]
+    { let _ = page; 0 }
+}
+
+#[cfg(any(feature = "secure", feature = "debug", debug_assertions))]
+unsafe fn quarantine_contains(page: *const Page, block: *const Block) -> bool {
+    #[cfg(feature = "secure")]
+    {
+        if (*page).quarantined == 0 || (*page).heap.is_null() { return false; }
+        let mut q: *mut Block = (*(*page).heap).quarantine.first;
+        while !q.is_null() {
+            if q as *const Block == block { return true; }
+            q = quarantine_next(q);
+        }
+        false
+    }
+    #[cfg(not(feature = "secure"))]
+    {
+        let _ = (page, block);
+        false
+    }
+}
+
+#[cfg(feature = "secure")]
+#[inline]
+unsafe fn block_page(block: *const Block) -> *mut Page {
+    segment_page_of(ptr_segment(block as *mut u8), block as *mut u8)
+}
+
+#[cfg(feature = "secure")]
+#[inline]
+unsafe fn quarantine_next(block: *const Block) -> *mut Block {
+    block_nextx(block_page(block), block)
+}
+
+#[cfg(feature = "secure")]
+#[inline]
+unsafe fn quarantine_set_next(block: *mut Block, next: *mut Block) {
+    block_set_next(block_page(block), block, next)
+}
+
+// Report a write to a block while it was in the quarantine.
+// The first word holds the quarantine link and is skipped.
+#[cfg(all(feature = "secure", feature = "debug"))]
+unsafe fn check_freed_fill(page: *const Page, block: *const Block) {
+    let start: *const u8 = (block as *const u8).add(core::mem::size_of::<Block>());
+    let n: usize = (*page).block_size - core::mem::size_of::<Block>();
+    if !(0..n).all(|i| *start.add(i) == MI_DEBUG_FREED) {
+        _error_message(libc::EFAULT, format_args!("write after free detected in block {:p} of page {:p} with block size {}", block, page, (*page).block_size));
+    }
+}
+
+// Give a block that was unlinked from the quarantine back to its page
+#[cfg(feature = "secure")]
+unsafe fn quarantine_release(q: *mut Quarantine, block: *mut Block) {
+    let page: *mut Page = block_page(block);
+    (*q).count -= 1;
+    (*q).size -= (*page).block_size;
+    (*page).quarantined -= 1;
+    #[cfg(feature = "debug")]
+    {
+        check_freed_fill(page, block);
+    }
+    _free_block_local(page, block);
+}
+
+// Release all quarantined blocks of `page`; the page may retire when the last one is released
+#[cfg(feature = "secure")]
+unsafe fn quarantine_release_page(q: *mut Quarantine, page: *mut Page) {
+    let mut prev: *mut Block = null_mut();
+    let mut block: *mut Block = (*q).first;
+    while !block.is_null() {
+        let next: *mut Block = quarantine_next(block);
+        if block_page(block) == page {
+            if prev.is_null() { (*q).first = next; } else { quarantine_set_next(prev, next); }
+            if (*q).last == block { (*q).last = prev; }
+            quarantine_release(q, block);
+        }
+        else {
+            prev = block;
+        }
+        block = next;
+    }
+}
+
+// Hold a locally freed block in the quarantine of `heap`.
+// Returns `false` if the quarantine is off and the block should be freed directly.
+#[cfg(feature = "secure")]
+unsafe fn quarantine_push(heap: *mut Heap, page: *mut Page, block: *mut Block) -> bool {
+    let max_count: usize = option_get(option_quarantine) as usize;
+    if max_count == 0 || heap.is_null() { return false; }
+    let q: *mut Quarantine = &mut (*heap).quarantine;
+    quarantine_set_next(block, null_mut());
+    if (*q).last.is_null() { (*q).first = block; } else { quarantine_set_next((*q).last, block); }
+    (*q).last = block;
+    (*q).count += 1;
+    (*q).size += (*page).block_size;
+    (*page).quarantined += 1;
+
+    // a page whose remaining blocks are all quarantined takes them back so it can retire
+    if (*page).used - (*page).thread_freed.load(Ordering::Relaxed) == (*page).quarantined as usize {
+        quarantine_release_page(q, page);
+    }
+
+    // release the oldest blocks while over the limits
+    let max_size: usize = option_get(option_quarantine_size) as usize;
+    while (*q).count > max_count || (max_size > 0 && (*q).size > max_size) {
+        let oldest: *mut Block = (*q).first;
+        (*q).first = quarantine_next(oldest);
+        if (*q).first.is_null() { (*q).last = null_mut(); }
+        quarantine_release(q, oldest);
+    }
+    true
+}
+
+// Visit the blocks in the quarantine of `heap`, oldest first
+#[cfg(feature = "secure")]
+pub unsafe fn _quarantine_visit<F: FnMut(*mut Block)>(heap: *mut Heap, mut visit: F) {
+    let mut block: *mut Block = (*heap).quarantine.first;
+    while !block.is_null() {
+        visit(block);
+        block = quarantine_next(block);
+    }
+}
+
+// Release every block in the quarantine of `heap`, for example before its pages are abandoned
+#[cfg(feature = "secure")]
+pub unsafe fn _quarantine_flush(heap: *mut Heap) {
+    let q: *mut Quarantine = &mut (*heap).quarantine;
+    while !(*q).first.is_null() {
+        let oldest: *mut Block = (*q).first;
+        (*q).first = quarantine_next(oldest);
+        quarantine_release(q, oldest);
+    }
+    (*q).last = null_mut();
+}
+
+/* -----------------------------------------------------------
+  Guarded allocations
+  With `option_guarded_sample_rate` set to N, one in every N allocations
+  with a size between `option_guarded_min` and `option_guarded_max` gets
+  a page of its own that ends in a protected guard page, and is placed
+  right against it so an overflow faults at once. When the owning thread
+  frees it the rest of the block is protected as well, and it only goes
+  back to its page after `MI_GUARDED_DELAY_MAX` later guarded frees of that
+  heap, so a use after free faults too. A block freed by another thread
+  goes back at once; its owner frees the page on its next guarded
+  allocation. Small overflows within the `MI_MAX_ALIGN_SIZE` rounding of
+  the size are not caught.
+----------------------------------------------------------- */
+
+// Should this allocation be guarded?
+#[inline]
+unsafe fn guarded_sample(heap: *mut Heap, size: usize) -> bool {
+    let rate: usize = option_get(option_guarded_sample_rate) as usize;
+    if rate == 0 || !heap_is_initialized(heap) { return false; }
+    let max: usize = option_get(option_guarded_max) as usize;
+    if size < option_get(option_guarded_min) as usize || (max > 0 && size > max) { return false; }
+    // the block is handed out near its end, which must be in the part of the segment that
+    // `is_in_heap_region` covers (the first `MI_SEGMENT_SIZE` bytes) so it can be freed
+    if size > MI_SEGMENT_SIZE / 2 { return false; }
+    (*heap).guarded_sample_count += 1;
+    if (*heap).guarded_sample_count < rate { return false; }
+    (*heap).guarded_sample_count = 0;
+    true
+}
+
+unsafe fn guarded_malloc(heap: *mut Heap, size: usize) -> *mut u8 {
+    _page_guarded_collect(heap);
+    let usable: usize = (size.max(1) + MI_MAX_ALIGN_SIZE - 1) & !(MI_MAX_ALIGN_SIZE - 1);
+    let page: *mut Page = _page_guarded_alloc(heap, usable);
+    if page.is_null() { return null_mut(); }
+
+    let block: *mut Block = (*page).free;
+    (*page).free = block_next(page, block);
+    (*page).used += 1;
+    (*page).flags.set_has_aligned(true);  // we hand out a pointer inside the block
+    (*page_segment(page)).guarded_size = usable;
+    let p: *mut u8 = _guarded_block_ptr(page, block);
+    _stat_increase(&mut (*(*heap).tld).stats.guarded, 1);
+    #[cfg(feature = "debug")]
+    {
+        core::ptr::write_bytes(p, MI_DEBUG_UNINIT, usable);
+    }
+    p
+}
+
+// The pointer handed out for the block of a guarded page
+pub unsafe fn _guarded_block_ptr(page: *const Page, block: *const Block) -> *mut u8 {
+    guarded_guard(page, block).sub((*page_segment(page)).guarded_size)
+}
+
+// The guard page that ends a guarded block; it is OS page aligned
+#[inline]
+unsafe fn guarded_guard(page: *const Page, block: *const Block) -> *mut u8 {
+    (block as *mut u8).add((*page).block_size - os_page_size())
+}
+
+// The whole OS pages of a guarded block in front of its guard page: those
+// can be protected while the block is freed (the block start may not be aligned)
+unsafe fn guarded_body(page: *const Page, block: *const Block) -> (*mut u8, usize) {
+    let psize: usize = os_page_size();
+    let start: usize = (block as usize + psize - 1) & !(psize - 1);
+    let guard: usize = guarded_guard(page, block) as usize;
+    (start as *mut u8, guard.saturating_sub(start))
+}
+
+// Protect a freed guarded block and release the oldest delayed one of the heap.
+// Blocks freed by another thread are released at once, as the heap is not ours.
+unsafe fn guarded_free(page: *mut Page, block: *mut Block) {
+    let segment: *mut Segment = page_segment(page);
+    if (*segment).thread_id != thread_id() {
+        // counted in the stats of this thread (or the process) as the owner's are not ours
+        let own: *mut Heap = get_default_heap();
+        let stats: *mut Stats = if heap_is_initialized(own) { &mut (*(*own).tld).stats } else { addr_of_mut!(stats_main) };
+        _stat_decrease(&mut (*stats).guarded, 1);
+        free_block_mt(page, block);
+        return;
+    }
+    let heap: *mut Heap = (*page).heap;
+    if (*heap).guarded_delayed.iter().any(|&d| d == block) {
+        _error_message(libc::EFAULT, format_args!("double free detected of guarded block {:p} with size {}", block, (*page).block_size));
+        return;
+    }
+    _stat_decrease(&mut (*(*heap).tld).stats.guarded, 1);
+    let (body, body_size) = guarded_body(page, block);
+    if body_size > 0 { _os_protect(body, body_size); }
+    let i: usize = (*heap).guarded_delayed_next;
+    (*heap).guarded_delayed_next = (i + 1) % MI_GUARDED_DELAY_MAX;
+    let oldest: *mut Block = core::mem::replace(&mut (*heap).guarded_delayed[i], block);
+    if !oldest.is_null() { guarded_release(oldest); }
+}
+
+// Give a delayed guarded block back to its page; the page is then all free and
+// retires, which unprotects the guard page as well.
+unsafe fn guarded_release(block: *mut Block) {
+    let segment: *mut Segment = ptr_segment(block as *mut u8);
+    let page: *mut Page = segment_page_of(segment, block as *mut u8);
+    let (body, body_size) = guarded_body(page, block);
+    if body_size > 0 { _os_unprotect(body, body_size); }
+    _free_block_local(page, block);
+}
+
+// Release the delayed guarded blocks of `heap`, for example before its pages are abandoned
+pub unsafe fn _guarded_flush(heap: *mut Heap) {
+    for i in 0..MI_GUARDED_DELAY_MAX {
+        let block: *mut Block = core::mem::replace(&mut (*heap).guarded_delayed[i], null_mut());
+        if !block.is_null() { guarded_release(block); }
+    }
+    (*heap).guarded_delayed_next = 0;
+}
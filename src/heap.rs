@@ -0,0 +1,474 @@
+use crate::{alloc::*, init::*, internal::*, page::*, page_queue::*, segment::*, types::*};
+
+use core::ptr::null_mut;
+use log::warn;
+
+/* -----------------------------------------------------------
+  Helpers
+----------------------------------------------------------- */
+
+// Visit all pages in a heap; returns `false` if `visit` stopped early.
+// The page may be removed from its queue by `visit`.
+unsafe fn heap_visit_pages<F>(heap: *mut Heap, mut visit: F) -> bool
+where
+    F: FnMut(*mut Heap, *mut PageQueue, *mut Page) -> bool,
+{
+    if heap.is_null() || (*heap).page_count == 0 { return true; }
+
+    // visit all pages
+    for i in 0..=MI_BIN_FULL {
+        let pq: *mut PageQueue = (*heap).pages.as_mut_ptr().add(i);
+        let mut page: *mut Page = (*pq).first;
+        while !page.is_null() {
+            let next: *mut Page = (*page).next; // save next in case the page gets removed from the queue
+            if !visit(heap, pq, page) { return false; }
+            page = next;
+        }
+    }
+    true
+}
+
+/* -----------------------------------------------------------
+  Abandon the pages of a heap
+----------------------------------------------------------- */
+
+// Free the pages without blocks in use and abandon the others,
+// so other threads can reclaim them.
+pub unsafe fn _heap_abandon_pages(heap: *mut Heap) {
+    // quarantined and delayed guarded blocks go back to their pages first so those can be freed
+    #[cfg(feature = "secure")]
+    {
+        _quarantine_flush(heap);
+    }
+    _guarded_flush(heap);
+    heap_visit_pages(heap, |_heap, pq, page| {
+        if page_all_free(page) {
+            _page_free(page, pq, false);
+        }
+        else {
+            _page_abandon(page, pq);
+        }
+        true
+    });
+    debug_assert!((*heap).page_count == 0);
+}
+
+/// Hand the memory of `heap` back for other threads: pages without blocks
+/// in use are freed, the others are abandoned and can be reclaimed by any
+/// thread, and the segments cached by this thread are released.
+/// `heap` must belong to the current thread; it stays usable afterwards.
+pub unsafe fn heap_collect_abandon(heap: *mut Heap) {
+    if heap.is_null() || !heap_is_initialized(heap) { return; }
+    debug_assert!((*heap).thread_id == thread_id());
+    _heap_abandon_pages(heap);
+    _segment_thread_collect(&mut (*(*heap).tld).segments);
+}
+
+/* -----------------------------------------------------------
+  Heap check
+----------------------------------------------------------- */
+
+/// The result of `heap_check`: what was checked and the problems found.
+/// Every problem is also logged as a warning.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct HeapCheck {
+    /// Number of pages checked.
+    pub pages: usize,
+    /// Number of segments checked, including the cached ones.
+    pub segments: usize,
+    /// Number of blocks in use (not counting blocks freed by other threads).
+    pub blocks_used: usize,
+    /// Number of blocks in the `free`, `local_free` and `thread_free` lists.
+    pub blocks_free: usize,
+    /// Pages in the wrong queue, or owned by another heap.
+    pub misplaced_pages: usize,
+    /// Free list entries outside their page or not at a block boundary.
+    pub invalid_blocks: usize,
+    /// Blocks that are twice in a free list (a cycle), or in more than one of them.
+    pub duplicate_blocks: usize,
+    /// Pages whose free blocks and `used` do not add up to their `capacity`.
+    pub count_mismatches: usize,
+    /// Segments with a wrong cookie, owner or page count.
+    pub invalid_segments: usize,
+}
+
+impl HeapCheck {
+    /// Returns `true` if no problems were found.
+    pub fn is_valid(&self) -> bool {
+        self.misplaced_pages == 0 && self.invalid_blocks == 0 && self.duplicate_blocks == 0 &&
+        self.count_mismatches == 0 && self.invalid_segments == 0
+    }
+}
+
+// Count the blocks of a free list of `page`, checking that each one is a block of the page
+// and is not in `free_map` yet: the blocks of all free lists of the page are marked there,
+// so a block that is twice in one list (a cycle) or in two of the lists is reported.
+unsafe fn check_free_list(page: *const Page, mut block: *mut Block, free_map: &mut [usize], report: &mut HeapCheck) -> usize {
+    let start: usize = page_start(page_segment(page), page, null_mut()) as usize;
+    let end: usize = start + (*page).capacity as usize * (*page).block_size;
+    let mut count: usize = 0;
+    while !block.is_null() {
+        if (block as usize) < start || block as usize >= end || (block as usize - start) % (*page).block_size != 0 {
+            warn!("heap check: free list of page {:p} contains an invalid block {:p}", page, block);
+            report.invalid_blocks += 1;
+            break;
+        }
+        let i: usize = (block as usize - start) / (*page).block_size;
+        if free_map[i / MI_INTPTR_BITS] & (1 << (i % MI_INTPTR_BITS)) != 0 {
+            warn!("heap check: block {:p} is in the free lists of page {:p} more than once", block, page);
+            report.duplicate_blocks += 1;
+            break;
+        }
+        free_map[i / MI_INTPTR_BITS] |= 1 << (i % MI_INTPTR_BITS);
+        count += 1;
+        block = block_nextx(page, block);
+    }
+    count
+}
+
+// Check a segment header; `heap` is null for cached segments, which must be empty
+unsafe fn check_segment(heap: *const Heap, segment: *const Segment, report: &mut HeapCheck) {
+    report.segments += 1;
+    if ptr_cookie(segment as *const u8) != (*segment).cookie {
+        warn!("heap check: segment {:p} has an invalid cookie", segment);
+        report.invalid_segments += 1;
+        return;  // the rest of the header can not be trusted
+    }
+    let mut used: usize = 0;
+    for i in 0..(*segment).capacity {
+        if (*(*segment).pages.add(i)).segment_in_use { used += 1; }
+    }
+    let owner_ok: bool = if heap.is_null() { (*segment).used == 0 } else { (*segment).thread_id == (*heap).thread_id };
+    if used != (*segment).used || (*segment).abandoned > (*segment).used || !owner_ok {
+        warn!("heap check: segment {:p} has {} of {} pages in use (counted {}), {} abandoned, thread {}",
+              segment, (*segment).used, (*segment).capacity, used, (*segment).abandoned, (*segment).thread_id);
+        report.invalid_segments += 1;
+    }
+}
+
+// Is `page` the first page of its segment that belongs to `heap`? Each segment is checked once, at that page.
+unsafe fn is_first_heap_page(heap: *const Heap, segment: *const Segment, page: *const Page) -> bool {
+    for i in 0..(*segment).capacity {
+        let p: *const Page = (*segment).pages.add(i);
+        if (*p).segment_in_use && (*p).heap as *const Heap == heap { return p == page; }
+    }
+    false
+}
+
+/// Check the consistency of `heap`: every page in its queues with its
+/// free lists, the segments of those pages, and the segments cached by
+/// its thread. `heap` must belong to the current thread.
+pub unsafe fn heap_check(heap: *mut Heap) -> HeapCheck {
+    let mut report: HeapCheck = HeapCheck::default();
+    if heap.is_null() || !heap_is_initialized(heap) { return report; }
+    debug_assert!((*heap).thread_id == thread_id());
+
+    heap_visit_pages(heap, |heap, pq, page| {
+        report.pages += 1;
+        let segment: *mut Segment = page_segment(page);
+        if ptr_cookie(segment as *const u8) != (*segment).cookie {
+            warn!("heap check: page {:p} is in a segment {:p} with an invalid cookie", page, segment);
+            report.invalid_segments += 1;
+            return true;
+        }
+        if is_first_heap_page(heap, segment, page) { check_segment(heap, segment, &mut report); }
+
+        if (*page).heap != heap || !(*page).segment_in_use || pq != _page_queue_of(heap, page) {
+            warn!("heap check: page {:p} with block size {} is misplaced (heap {:p}, in use {})",
+                  page, (*page).block_size, (*page).heap, (*page).segment_in_use);
+            report.misplaced_pages += 1;
+            return true;
+        }
+
+        if (*page).capacity as usize > MI_MAX_BLOCKS {
+            warn!("heap check: page {:p} has a capacity of {} blocks", page, (*page).capacity);
+            report.count_mismatches += 1;
+            return true;
+        }
+
+        // blocks freed by other threads are still counted in `used`
+        let mut free_map: [usize; MI_MAX_BLOCKS / MI_INTPTR_BITS] = [0; MI_MAX_BLOCKS / MI_INTPTR_BITS];
+        let nfree: usize = check_free_list(page, (*page).free, &mut free_map, &mut report) +
+                           check_free_list(page, (*page).local_free, &mut free_map, &mut report);
+        let nthread: usize = check_free_list(page, (*page).thread_free.head(), &mut free_map, &mut report);
+        if (*page).capacity > (*page).reserved || (*page).used + nfree != (*page).capacity as usize || nthread > (*page).used {
+            warn!("heap check: page {:p} has {} used and {} free blocks (and {} freed by other threads) but a capacity of {} (reserved {})",
+                  page, (*page).used, nfree, nthread, (*page).capacity, (*page).reserved);
+            report.count_mismatches += 1;
+        }
+        report.blocks_free += nfree + nthread;
+        report.blocks_used += (*page).used.saturating_sub(nthread);
+        true
+    });
+
+    // the segments cached by this thread (the purge thread may be visiting them)
+    let tld: *mut SegmentsTld = &mut (*(*heap).tld).segments;
+    lock_acquire(&(*tld).lock);
+    let mut segment: *mut Segment = (*tld).cache.first;
+    while !segment.is_null() {
+        check_segment(null_mut(), segment, &mut report);
+        segment = (*segment).next;
+    }
+    lock_release(&(*tld).lock);
+    report
+}
+
+/// The default heap of the current thread, set up on first use.
+/// This is the heap to pass to `heap_check`, `heap_collect_abandon`,
+/// `heap_visit_blocks` and `heap_contains_block`.
+pub unsafe fn heap_get_default() -> *mut Heap {
+    if !heap_is_initialized(get_default_heap()) { thread_init(); }
+    get_default_heap()
+}
+
+/// Check the consistency of the default heap of the current thread; see `heap_check`.
+pub unsafe fn check_heap() -> HeapCheck {
+    heap_check(get_default_heap())
+}
+
+/* -----------------------------------------------------------
+  Leak report
+----------------------------------------------------------- */
+
+// Number of blocks of a page that are still allocated: `used` without the blocks
+// freed by other threads that are not collected yet (nor those in the quarantine)
+pub unsafe fn _page_live_count(page: *const Page) -> usize {
+    let mut live: usize = (*page).used;
+    let mut block: *mut Block = (*page).thread_free.head();
+    while !block.is_null() && live > 0 {
+        live -= 1;
+        block = block_nextx(page, block);
+    }
+    #[cfg(feature = "secure")]
+    {
+        live = live.saturating_sub((*page).quarantined as usize);
+    }
+    live
+}
+
+/// The blocks still allocated, as found by `leaks_report`.
+#[derive(Clone, Copy, Debug)]
+pub struct Leaks {
+    // by size class (all huge blocks share the last one)
+    blocks: [usize; MI_BIN_HUGE + 1],
+    bytes: [usize; MI_BIN_HUGE + 1],
+    block_size: [usize; MI_BIN_HUGE + 1],  // largest block size seen in the size class
+    segments: usize,                       // segments with blocks still allocated
+}
+
+impl Leaks {
+    pub const fn new() -> Leaks {
+        Leaks {
+            blocks: [0; MI_BIN_HUGE + 1],
+            bytes: [0; MI_BIN_HUGE + 1],
+            block_size: [0; MI_BIN_HUGE + 1],
+            segments: 0,
+        }
+    }
+
+    // Count the blocks still allocated in `segment`, and list it if there are any
+    pub unsafe fn add_segment(&mut self, segment: *const Segment) {
+        let mut blocks: usize = 0;
+        let mut bytes: usize = 0;
+        for i in 0..(*segment).capacity {
+            let page: *const Page = (*segment).pages.add(i);
+            if !(*page).segment_in_use { continue; }
+            let live: usize = _page_live_count(page);
+            if live == 0 { continue; }
+            let bin: usize = core::cmp::min(_bin((*page).block_size) as usize, MI_BIN_HUGE);
+            self.blocks[bin] += live;
+            self.bytes[bin] += live * (*page).block_size;
+            self.block_size[bin] = core::cmp::max(self.block_size[bin], (*page).block_size);
+            blocks += live;
+            bytes += live * (*page).block_size;
+        }
+        if blocks > 0 {
+            self.segments += 1;
+            warn!("leaked: segment {:p} (thread {:#x}): {} blocks, {} bytes", segment, (*segment).thread_id, blocks, bytes);
+        }
+    }
+
+    /// Number of blocks still allocated.
+    pub fn blocks(&self) -> usize {
+        self.blocks.iter().sum()
+    }
+
+    /// Bytes in the blocks still allocated, counting whole blocks.
+    pub fn bytes(&self) -> usize {
+        self.bytes.iter().sum()
+    }
+
+    /// Number of segments with blocks still allocated.
+    pub fn segments(&self) -> usize {
+        self.segments
+    }
+
+    pub fn print(&self) {
+        let blocks: usize = self.blocks();
+        if blocks == 0 {
+            log::debug!("no blocks leaked");
+            return;
+        }
+        for bin in 0..=MI_BIN_HUGE {
+            if self.blocks[bin] == 0 { continue; }
+            if bin == MI_BIN_HUGE {
+                warn!("leaked: huge blocks: {} blocks, {} bytes", self.blocks[bin], self.bytes[bin]);
+            }
+            else {
+                warn!("leaked: block size {}: {} blocks, {} bytes", self.block_size[bin], self.blocks[bin], self.bytes[bin]);
+            }
+        }
+        warn!("leaked: total: {} blocks, {} bytes in {} segments", blocks, self.bytes(), self.segments);
+    }
+}
+
+// Add the blocks still allocated in the segments of `heap` to `leaks`
+pub unsafe fn _heap_leaks_collect(heap: *mut Heap, leaks: &mut Leaks) {
+    heap_visit_pages(heap, |heap, _pq, page| {
+        let segment: *mut Segment = page_segment(page);
+        if is_first_heap_page(heap, segment, page) { leaks.add_segment(segment); }
+        true
+    });
+}
+
+/* -----------------------------------------------------------
+  Visit the blocks of a heap
+----------------------------------------------------------- */
+
+/// An area of a heap: the blocks of one size in a page.
+#[derive(Clone, Copy, Debug)]
+pub struct HeapArea {
+    /// Start of the first block.
+    pub blocks: *mut u8,
+    /// Bytes reserved for blocks.
+    pub reserved: usize,
+    /// Bytes committed for blocks.
+    pub committed: usize,
+    /// Number of blocks in use.
+    pub used: usize,
+    /// Size of each block.
+    pub block_size: usize,
+}
+
+// The most blocks a page can have: a small page with the smallest block size
+const MI_MAX_BLOCKS: usize = MI_SMALL_PAGE_SIZE / MI_INTPTR_SIZE;
+const MI_INTPTR_BITS: usize = 8 * MI_INTPTR_SIZE;
+
+// Index of `block` in `page` whose blocks start at `start`, or `None` if it is not one of its blocks
+unsafe fn page_block_index(page: *const Page, start: *mut u8, block: *const Block) -> Option<usize> {
+    let offset: usize = (block as usize).checked_sub(start as usize)?;
+    let i: usize = offset / (*page).block_size;
+    if offset % (*page).block_size != 0 || i >= (*page).capacity as usize { return None; }
+    Some(i)
+}
+
+// Mark the blocks of a free list in `free_map`. A list longer than the capacity
+// stops there, and one with an entry that is not a block of the page stops at it.
+unsafe fn page_mark_free(page: *const Page, start: *mut u8, mut block: *mut Block, free_map: &mut [usize]) {
+    let mut count: usize = 0;
+    while !block.is_null() && count < (*page).capacity as usize {
+        let i: usize = match page_block_index(page, start, block) {
+            Some(i) => i,
+            None => {
+                warn!("heap visit: free list of page {:p} contains an invalid block {:p}", page, block);
+                return;
+            }
+        };
+        free_map[i / MI_INTPTR_BITS] |= 1 << (i % MI_INTPTR_BITS);
+        count += 1;
+        block = block_nextx(page, block);
+    }
+}
+
+// Visit the blocks of `page` that are still allocated: those that are in none of its free lists
+unsafe fn page_visit_blocks<F>(heap: *mut Heap, page: *const Page, area: &HeapArea, visit: &mut F) -> bool
+where
+    F: FnMut(&HeapArea, Option<*mut u8>) -> bool,
+{
+    if area.used == 0 { return true; }
+    let bsize: usize = (*page).block_size;
+    let capacity: usize = (*page).capacity as usize;
+    if capacity == 1 {
+        // huge and guarded pages; a guarded block is handed out right in front of its guard page
+        let block: *mut u8 = if (*page_segment(page)).is_guarded {
+            _guarded_block_ptr(page, area.blocks as *const Block)
+        } else {
+            area.blocks
+        };
+        return visit(area, Some(block));
+    }
+    debug_assert!(capacity <= MI_MAX_BLOCKS);
+    if capacity > MI_MAX_BLOCKS { return true; }
+
+    let mut free_map: [usize; MI_MAX_BLOCKS / MI_INTPTR_BITS] = [0; MI_MAX_BLOCKS / MI_INTPTR_BITS];
+    page_mark_free(page, area.blocks, (*page).free, &mut free_map);
+    page_mark_free(page, area.blocks, (*page).local_free, &mut free_map);
+    page_mark_free(page, area.blocks, (*page).thread_free.head(), &mut free_map);
+    #[cfg(feature = "secure")]
+    {
+        if (*page).quarantined > 0 {
+            _quarantine_visit(heap, |block| {
+                // the quarantine holds blocks of any segment: check the segment before the page
+                if ptr_segment(block as *const u8) == page_segment(page) &&
+                   segment_page_of(page_segment(page), block as *const u8) as *const Page == page {
+                    if let Some(i) = page_block_index(page, area.blocks, block) {
+                        free_map[i / MI_INTPTR_BITS] |= 1 << (i % MI_INTPTR_BITS);
+                    }
+                }
+            });
+        }
+    }
+    #[cfg(not(feature = "secure"))]
+    {
+        let _ = heap;
+    }
+
+    for i in 0..capacity {
+        if free_map[i / MI_INTPTR_BITS] & (1 << (i % MI_INTPTR_BITS)) != 0 { continue; }
+        if !visit(area, Some(area.blocks.add(i * bsize))) { return false; }
+    }
+    true
+}
+
+/// Visit the areas of `heap`, and with `visit_blocks` also every block in use.
+/// `visit` is called with `None` for each area, then with the pointer handed
+/// out for each block in use in that area, and stops the walk by returning `false`.
+/// Returns `false` if the walk was stopped. `heap` must belong to the current
+/// thread, and `visit` must not allocate or free in it.
+pub unsafe fn heap_visit_blocks<F>(heap: *mut Heap, visit_blocks: bool, mut visit: F) -> bool
+where
+    F: FnMut(&HeapArea, Option<*mut u8>) -> bool,
+{
+    if heap.is_null() || !heap_is_initialized(heap) { return true; }
+    debug_assert!((*heap).thread_id == thread_id());
+    heap_visit_pages(heap, |heap, _pq, page| {
+        let block_size: usize = (*page).block_size;
+        let area: HeapArea = HeapArea {
+            blocks: page_start(page_segment(page), page, null_mut()),
+            reserved: (*page).reserved as usize * block_size,
+            committed: (*page).capacity as usize * block_size,
+            used: _page_live_count(page),
+            block_size,
+        };
+        if !visit(&area, None) { return false; }
+        !visit_blocks || page_visit_blocks(heap, page, &area, &mut visit)
+    })
+}
+
+/* -----------------------------------------------------------
+  Pointer ownership
+----------------------------------------------------------- */
+
+/// Returns `true` if `p` points into a block of a page that belongs to `heap`.
+/// Any pointer can be passed: memory outside the segments of this allocator
+/// is never accessed. A block freed by another thread may still count as
+/// contained until `heap` collects it.
+pub unsafe fn heap_contains_block(heap: *const Heap, p: *const u8) -> bool {
+    if heap.is_null() || !is_in_heap_region(p) { return false; }
+    let segment: *mut Segment = ptr_segment(p);
+    if segment.is_null() || ptr_cookie(segment as *const u8) != (*segment).cookie { return false; }
+    let page: *mut Page = segment_page_of(segment, p);
+    if !(*page).segment_in_use || (*page).heap as *const Heap != heap { return false; }
+    let start: *mut u8 = page_start(segment, page, null_mut());
+    p >= start as *const u8 && p < start.add((*page).capacity as usize * (*page).block_size) as *const u8
+}
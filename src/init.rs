@@ -1,7 +1,14 @@
-use crate::types::*;
+use crate::{heap::*, internal::*, options::*, os::*, random::*, segment::*, stats::*, types::*};
+
+use core::{
+    mem::size_of,
+    ptr::{addr_of_mut, null_mut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use log::warn;
 
 // Empty page used to initialize the small free pages array
-const page_empty: Page = unimplemented!();
+pub static mut page_empty: Page = Page::new();
 
 // --------------------------------------------------------
 // Statically allocate an empty heap as the initial
@@ -12,25 +19,77 @@ const page_empty: Page = unimplemented!();
 // may lead to allocation itself on some platforms)
 // --------------------------------------------------------
 
-const heap_empty: Heap = unimplemented!();
-
-// thread-local
-static mut heap_default: *mut Heap = &mut heap_empty as _;
+pub static mut heap_empty: Heap = Heap::new(null_mut(), addr_of_mut!(page_empty));
 
 
-static tld_main: Tld = unimplemented!();
+static mut tld_main: Tld = Tld::new(addr_of_mut!(heap_main), unsafe { addr_of_mut!(tld_main.stats) });
 
-static mut heap_main: Heap = unimplemented!();
+pub static mut heap_main: Heap = Heap::new(addr_of_mut!(tld_main), addr_of_mut!(page_empty));
 
-static mut process_is_initialized: bool = false;  // set to `true` in `process_init`.
+pub static mut process_is_initialized: bool = false;  // set to `true` in `process_init`.
 
-static mut stats_main: Stats = unimplemented!();
+pub static mut stats_main: Stats = Stats::new();
 
 
-unsafe fn ptr_cookie(p: *const u8) -> usize {
+pub unsafe fn ptr_cookie(p: *const u8) -> usize {
   p as usize ^ heap_main.cookie
 }
 
+/* -----------------------------------------------------------
+  The default heap of each thread
+  Kept in a thread local key that is created in `process_init`
+  (`get_default_heap` uses `heap_main` until then). Without a value
+  a thread uses `heap_empty`, except the main thread which uses
+  `heap_main`. Only the thread itself sets its default heap.
+----------------------------------------------------------- */
+
+#[cfg(not(windows))]
+static mut heap_default_key: libc::pthread_key_t = 0;
+
+#[cfg(windows)]
+static mut heap_default_key: u32 = 0;
+
+#[cfg(not(windows))]
+unsafe fn heap_default_setup() -> bool {
+  libc::pthread_key_create(addr_of_mut!(heap_default_key), None) == 0
+}
+
+#[cfg(windows)]
+unsafe fn heap_default_setup() -> bool {
+  heap_default_key = winapi::um::processthreadsapi::TlsAlloc();
+  heap_default_key != winapi::um::winbase::TLS_OUT_OF_INDEXES
+}
+
+#[cfg(not(windows))]
+#[inline]
+unsafe fn heap_default_value() -> *mut Heap {
+  libc::pthread_getspecific(heap_default_key) as *mut Heap
+}
+
+#[cfg(windows)]
+#[inline]
+unsafe fn heap_default_value() -> *mut Heap {
+  winapi::um::processthreadsapi::TlsGetValue(heap_default_key) as *mut Heap
+}
+
+// The default heap of the current thread; only valid after `process_init`
+#[inline]
+pub unsafe fn _heap_default() -> *mut Heap {
+  let heap: *mut Heap = heap_default_value();
+  if !heap.is_null() { heap }
+  else if is_main_thread() { addr_of_mut!(heap_main) }
+  else { addr_of_mut!(heap_empty) }
+}
+
+// Set the default heap of the current thread; `heap_empty` clears it
+unsafe fn heap_default_set(heap: *mut Heap) {
+  let value: *mut Heap = if heap == addr_of_mut!(heap_empty) { null_mut() } else { heap };
+  #[cfg(not(windows))]
+  libc::pthread_setspecific(heap_default_key, value as *const libc::c_void);
+  #[cfg(windows)]
+  winapi::um::processthreadsapi::TlsSetValue(heap_default_key, value as _);
+}
+
 /* -----------------------------------------------------------
   Initialization and freeing of the thread local heaps
 ----------------------------------------------------------- */
@@ -42,70 +101,275 @@ struct ThreadData {
 
 // Initialize the thread local default heap, called from `thread_init`
 unsafe fn heap_init() -> bool {
-    if (heap_is_initialized(heap_default)) { return true; }
-    if (is_main_thread()) {
+    if heap_is_initialized(_heap_default()) { return true; }
+    if is_main_thread() {
         // the main heap is statically allocated
-        heap_default = &heap_main;
-        debug_assert!(heap_default->tld->heap_backing == heap_default);
+        heap_default_set(addr_of_mut!(heap_main));
+        debug_assert!((*(*_heap_default()).tld).heap_backing == _heap_default());
     }
     else {
         // use `os_alloc` to allocate directly from the OS
-        let td: *mut ThreadData = os_alloc(size_of::<ThreadData>(), &stats_main); // Todo: more efficient allocation?
-        if (td == NULL) {
-            error!("failed to allocate thread local heap memory");
+        let td: *mut ThreadData = _os_alloc(size_of::<ThreadData>(), addr_of_mut!(stats_main)) as *mut ThreadData; // Todo: more efficient allocation?
+        if td.is_null() {
+            warn!("failed to allocate thread local heap memory");
             return false;
         }
-        tld_t*  tld = &(*td).tld;
-        heap_t* heap = &(*td).heap;
-        memcpy(heap, &heap_empty, sizeof(*heap));
+        let tld: *mut Tld = addr_of_mut!((*td).tld);
+        let heap: *mut Heap = addr_of_mut!((*td).heap);
+        heap.write(Heap::new(tld, addr_of_mut!(page_empty)));
         (*heap).thread_id = thread_id();
         (*heap).random = random_init((*heap).thread_id);
-        (*heap).cookie = (heap ^ heap_random(heap)) | 1;
-        (*heap).tld = tld;
-        memset(tld, 0, sizeof(*tld));
-        (*tld).heap_backing = heap;
-        (*tld).segments.stats = &(*tld).stats;
-        (*tld).os.stats = &(*tld).stats;
-        heap_default = heap;
+        (*heap).cookie = (heap as usize ^ heap_random(heap)) | 1;
+        tld.write(Tld::new(heap, addr_of_mut!((*tld).stats)));
+        heap_default_set(heap);
+        thread_set_auto_done(heap);
+        tld_register(tld);
     }
     return false;
 }
 
 // Free the thread local default heap (called from `thread_done`)
 unsafe fn heap_done() -> bool {
-    let heap: *mut Heap = heap_default;
-    if (!heap_is_initialized(heap)) {return true;}
+    let heap: *mut Heap = _heap_default();
+    if !heap_is_initialized(heap) { return true; }
 
     // reset default heap
-    heap_default = (is_main_thread() ? &heap_main : &heap_empty);
+    heap_default_set(if is_main_thread() { addr_of_mut!(heap_main) } else { addr_of_mut!(heap_empty) });
 
     // todo: delete all non-backing heaps?
 
     // switch to backing heap and free it
-    heap = (*(*heap).tld).heap_backing;
-    if (!heap_is_initialized(heap)) {return false;}
+    heap_backing_done((*(*heap).tld).heap_backing);
+    return false;
+}
 
-    // collect if not the main thread 
-    if (heap != &heap_main) {
-        heap_collect_abandon(heap);
-    }
+// Hand back the memory of a backing heap and free it (unless it is the main heap)
+unsafe fn heap_backing_done(heap: *mut Heap) {
+    if !heap_is_initialized(heap) { return; }
+
+    // abandon the pages, also of the main heap: its blocks may still be in use
+    heap_collect_abandon(heap);
 
-    // merge stats
-    stats_done(&(*(*heap).tld).stats);
+    // merge stats (the thread is counted in `stats_main` from here on)
+    stats_done(&mut (*(*heap).tld).stats);
 
     // free if not the main thread
-    if (heap != &heap_main) {
-        os_free(heap, sizeof(thread_data_t), &stats_main);
+    if heap != addr_of_mut!(heap_main) {
+        _stat_decrease(addr_of_mut!(stats_main.threads), 1);
+        // the exit destructor must not find the freed heap if this ran from `thread_done`
+        thread_clear_auto_done();
+        tld_unregister((*heap).tld);
+        _os_free(heap as *mut u8, size_of::<ThreadData>(), addr_of_mut!(stats_main));
     }
     else {
-        heap_destroy_pages(heap);
-        debug_assert!(heap->tld->heap_backing == &heap_main);
+        debug_assert!((*(*heap).tld).heap_backing == addr_of_mut!(heap_main));
     }
-    return false;
 }
 
 
 
+/* -----------------------------------------------------------
+  List of the thread local data of all threads,
+  so the purge thread can visit their segments
+----------------------------------------------------------- */
+
+static tld_list_lock: AtomicBool = AtomicBool::new(false);
+static mut tld_list: *mut Tld = null_mut();
+
+unsafe fn tld_register(tld: *mut Tld) {
+    lock_acquire(&tld_list_lock);
+    (*tld).prev = null_mut();
+    (*tld).next = tld_list;
+    if !tld_list.is_null() { (*tld_list).prev = tld; }
+    tld_list = tld;
+    lock_release(&tld_list_lock);
+}
+
+unsafe fn tld_unregister(tld: *mut Tld) {
+    lock_acquire(&tld_list_lock);
+    if !(*tld).prev.is_null() { (*(*tld).prev).next = (*tld).next; } else { tld_list = (*tld).next; }
+    if !(*tld).next.is_null() { (*(*tld).next).prev = (*tld).prev; }
+    (*tld).next = null_mut();
+    (*tld).prev = null_mut();
+    lock_release(&tld_list_lock);
+}
+
+/* -----------------------------------------------------------
+  Purge thread
+  Delayed resets only run when a thread allocates, so the memory
+  of idle threads is never released. With `option_purge_thread`,
+  a background thread visits all threads and the abandoned
+  segments periodically instead.
+----------------------------------------------------------- */
+
+static purge_thread_stop: AtomicBool = AtomicBool::new(false);
+static purge_thread_restart: AtomicBool = AtomicBool::new(false);  // set in a forked child
+#[cfg(not(windows))]
+static mut purge_thread: Option<libc::pthread_t> = None;
+
+// Held while purging takes abandoned or globally cached segments out of their list
+static purge_lock: AtomicBool = AtomicBool::new(false);
+
+unsafe fn purge_visit_all() {
+    lock_acquire(&tld_list_lock);
+    let mut tld: *mut Tld = tld_list;
+    while !tld.is_null() {
+        _segment_delayed_purge(&mut (*tld).segments, false);
+        tld = (*tld).next;
+    }
+    lock_release(&tld_list_lock);
+    lock_acquire(&purge_lock);
+    _segment_abandoned_purge(addr_of_mut!(stats_main));
+    _segment_cache_global_purge(addr_of_mut!(stats_main));
+    lock_release(&purge_lock);
+}
+
+// Start the purge thread again in a forked child; called from the allocation slow path
+pub unsafe fn _purge_thread_check() {
+    if purge_thread_restart.load(Ordering::Relaxed) && purge_thread_restart.swap(false, Ordering::AcqRel) {
+        purge_thread_start();
+    }
+}
+
+#[cfg(not(windows))]
+extern "C" fn purge_thread_main(_arg: *mut libc::c_void) -> *mut libc::c_void {
+    // wake up often enough to stop quickly, but only purge every half reset delay
+    const WAKEUP_MSECS: i64 = 10;
+    let interval: i64 = core::cmp::max(option_get(option_reset_delay) as i64 / 2, WAKEUP_MSECS);
+    let mut next_purge: i64 = _clock_now() + interval;
+    while !purge_thread_stop.load(Ordering::Acquire) {
+        let t = libc::timespec { tv_sec: 0, tv_nsec: (WAKEUP_MSECS * 1_000_000) as _ };
+        unsafe { libc::nanosleep(&t, null_mut()); }
+        let now: i64 = _clock_now();
+        if now >= next_purge {
+            unsafe { purge_visit_all(); }
+            next_purge = now + interval;
+        }
+    }
+    null_mut()
+}
+
+#[cfg(not(windows))]
+unsafe fn purge_thread_start() {
+    let mut thread: libc::pthread_t = core::mem::zeroed();
+    if libc::pthread_create(&mut thread, core::ptr::null(), purge_thread_main, null_mut()) != 0 {
+        warn!("failed to start the purge thread: {}", errno::errno());
+        return;
+    }
+    purge_thread = Some(thread);
+}
+
+#[cfg(not(windows))]
+unsafe fn purge_thread_done() {
+    if let Some(thread) = purge_thread {
+        purge_thread = None;
+        purge_thread_stop.store(true, Ordering::Release);
+        libc::pthread_join(thread, null_mut());
+    }
+}
+
+// TODO: Use `CreateThread` for the purge thread on Windows
+#[cfg(windows)]
+unsafe fn purge_thread_start() {}
+
+#[cfg(windows)]
+unsafe fn purge_thread_done() {}
+
+/* -----------------------------------------------------------
+  Fork
+  Only the forking thread lives on in the child. The segments of the
+  other threads are abandoned there so the surviving thread can reclaim
+  them; their page queues are changed without a lock and may be halfway
+  through an update, so those are never walked.
+----------------------------------------------------------- */
+
+// No other thread may be halfway through changing the thread list or its segments,
+// nor may the purge thread hold abandoned or globally cached segments
+#[cfg(not(windows))]
+unsafe extern "C" fn fork_prepare() {
+    lock_acquire(&purge_lock);
+    lock_acquire(&tld_list_lock);
+    let mut tld: *mut Tld = tld_list;
+    while !tld.is_null() {
+        lock_acquire(&(*tld).segments.lock);
+        tld = (*tld).next;
+    }
+}
+
+#[cfg(not(windows))]
+unsafe extern "C" fn fork_parent() {
+    let mut tld: *mut Tld = tld_list;
+    while !tld.is_null() {
+        lock_release(&(*tld).segments.lock);
+        tld = (*tld).next;
+    }
+    lock_release(&tld_list_lock);
+    lock_release(&purge_lock);
+}
+
+#[cfg(not(windows))]
+unsafe extern "C" fn fork_child() {
+    lock_release(&tld_list_lock);
+    lock_release(&purge_lock);
+    _segment_fork_child();
+
+    // the surviving thread is the one that forked: it has the same thread id in the child
+    let current: usize = thread_id();
+    let mut tld: *mut Tld = tld_list;
+    while !tld.is_null() {
+        let next: *mut Tld = (*tld).next;
+        if (*(*tld).heap_backing).thread_id == current {
+            lock_release(&(*tld).segments.lock);
+        }
+        else {
+            fork_child_abandon(tld);
+        }
+        tld = next;
+    }
+    stats_reset();
+
+    // the purge thread of the parent is gone as well; creating a thread is
+    // not safe here, so it is started again on the next allocation
+    if purge_thread.take().is_some() {
+        purge_thread_stop.store(false, Ordering::Relaxed);
+        purge_thread_restart.store(true, Ordering::Release);
+    }
+}
+
+// Abandon the segments of a thread that does not exist in the child, while
+// its lock is still held from `fork_prepare`. No page refers to its heap
+// afterwards, so the heap is freed; the main heap is static and is emptied
+// instead, keeping its cookie.
+#[cfg(not(windows))]
+unsafe fn fork_child_abandon(tld: *mut Tld) {
+    let heap: *mut Heap = (*tld).heap_backing;
+    _segment_fork_abandon((*heap).thread_id, &mut (*tld).segments);
+    if tld == addr_of_mut!(tld_main) {
+        let (thread_id, cookie, random) = (heap_main.thread_id, heap_main.cookie, heap_main.random);
+        addr_of_mut!(heap_main).write(Heap::new(addr_of_mut!(tld_main), addr_of_mut!(page_empty)));
+        heap_main.thread_id = thread_id;
+        heap_main.cookie = cookie;
+        heap_main.random = random;
+        lock_release(&(*tld).segments.lock);
+    }
+    else {
+        tld_unregister(tld);
+        _os_free(heap as *mut u8, size_of::<ThreadData>(), addr_of_mut!(stats_main));
+    }
+}
+
+#[cfg(not(windows))]
+unsafe fn process_setup_fork() {
+    if libc::pthread_atfork(Some(fork_prepare), Some(fork_parent), Some(fork_child)) != 0 {
+        warn!("unable to register fork handlers; a forked child may find the heap in an inconsistent state");
+    }
+}
+
+#[cfg(windows)]
+unsafe fn process_setup_fork() {}
+
+
+
 // --------------------------------------------------------
 // Try to run `thread_done()` automatically so any memory
 // owned by the thread but not yet released can be abandoned
@@ -126,39 +390,80 @@ fn is_main_thread() -> bool {
   return (heap_main.thread_id == 0 || heap_main.thread_id == thread_id());
 }
 
-// This is called from the `malloc_generic`
-unsafe fn thread_init() {
+#[cfg(not(windows))]
+static mut process_thread_done_key: libc::pthread_key_t = 0;
+
+// Runs when a thread with a non-null value for the key exits. The value is the
+// backing heap of that thread, which is not necessarily its default heap.
+#[cfg(not(windows))]
+unsafe extern "C" fn pthread_done(value: *mut libc::c_void) {
+  let heap: *mut Heap = value as *mut Heap;
+  if heap.is_null() || !heap_is_initialized(heap) { return; }
+  if _heap_default() == heap { heap_default_set(addr_of_mut!(heap_empty)); }
+  #[cfg(debug_assertions)]
+  log::debug!("thread done: {:#x}", (*heap).thread_id);
+  heap_backing_done(heap);
+}
+
+#[cfg(not(windows))]
+unsafe fn process_setup_auto_thread_done() {
+  if libc::pthread_key_create(addr_of_mut!(process_thread_done_key), Some(pthread_done)) != 0 {
+    warn!("unable to create a key for thread termination; exiting threads will not abandon their heap");
+  }
+}
+
+// The key only gets a value in threads that set up a heap,
+// so other threads do not run the destructor at all.
+#[cfg(not(windows))]
+unsafe fn thread_set_auto_done(heap: *mut Heap) {
+  libc::pthread_setspecific(process_thread_done_key, heap as *const libc::c_void);
+}
+
+#[cfg(not(windows))]
+unsafe fn thread_clear_auto_done() {
+  libc::pthread_setspecific(process_thread_done_key, core::ptr::null());
+}
+
+// TODO: Use `FlsAlloc` with a destructor on Windows
+#[cfg(windows)]
+unsafe fn process_setup_auto_thread_done() {}
+
+#[cfg(windows)]
+unsafe fn thread_set_auto_done(_heap: *mut Heap) {}
+
+#[cfg(windows)]
+unsafe fn thread_clear_auto_done() {}
+
+/// Set up the heap of the current thread. This happens on the first
+/// allocation anyway, but a thread that called `thread_done` can use it
+/// to get a fresh heap right away.
+pub unsafe fn thread_init() {
   // ensure our process has started already
   process_init();
 
   // initialize the thread local default heap
-  if (heap_init()) {return;}  // returns true if already initialized
+  if heap_init() { return; }  // returns true if already initialized
 
   // don't further initialize for the main thread
-  if (is_main_thread()) {return;}
+  if is_main_thread() { return; }
 
-  stat_increase(&get_default_heap()->tld->stats.threads, 1);
+  _stat_increase(&mut (*(*get_default_heap()).tld).stats.threads, 1);
 
-  #if (DEBUG>0) // not in release mode as that leads to crashes on Windows dynamic override
-  verbose_message("thread init: 0x%zx\n", thread_id());
-  #endif
+  #[cfg(debug_assertions)]
+  log::debug!("thread init: {:#x}", thread_id());
 }
 
-void thread_done(void) attr_noexcept {
-  // stats
-  heap_t* heap = get_default_heap();
-  if (!is_main_thread() && heap_is_initialized(heap))  {
-    stat_decrease(&heap->tld->stats.threads, 1);
-  }
-
+/// Abandon the heap of the current thread so other threads can reclaim its
+/// pages, as happens when a thread exits. Useful for threads that park for
+/// a long time; the next allocation sets up a new heap.
+pub unsafe fn thread_done() {
   // abandon the thread local heap
-  if (heap_done()) return; // returns true if already ran
+  if heap_done() { return; }  // returns true if already ran
 
-  #if (DEBUG>0)
-  if (!is_main_thread()) {
-    verbose_message("thread done: 0x%zx\n", thread_id());
+  #[cfg(debug_assertions)]
+  if !is_main_thread() {
+    log::debug!("thread done: {:#x}", thread_id());
   }
-  #endif
 }
 
 
@@ -166,97 +471,87 @@ void thread_done(void) attr_noexcept {
 // Run functions on process init/done, and thread init/done
 // --------------------------------------------------------
 
-fn process_init() {
+pub unsafe fn process_init() {
   // ensure we are called once
-  if (process_is_initialized) {return;}
-  // access heap_default before setting process_is_initialized to ensure
-  // that the TLS slot is allocated without getting into recursion on macOS
-  // when using dynamic linking with interpose.
-  heap_t* h = heap_default;
+  if process_is_initialized { return; }
+  // the key must exist before `get_default_heap` looks at it
+  if !heap_default_setup() {
+    warn!("unable to create a thread local key for the default heap");
+    return;
+  }
+  // from here on allocations of the main thread go through `heap_main`
   process_is_initialized = true;
 
   heap_main.thread_id = thread_id();
-  verbose_message("process init: 0x%zx\n", heap_main.thread_id);
-  uintptr_t random = random_init(heap_main.thread_id)  ^ (uintptr_t)h;
-  #ifndef __APPLE__
-  heap_main.cookie = (uintptr_t)&heap_main ^ random;
-  #endif
+  log::debug!("process init: {:#x}", heap_main.thread_id);
+  let random: usize = random_init(heap_main.thread_id) ^ (addr_of_mut!(heap_default_key) as usize);
+  #[cfg(not(target_os = "macos"))]
+  {
+    heap_main.cookie = (addr_of_mut!(heap_main) as usize) ^ random;
+  }
   heap_main.random = random_shuffle(random);
-  #if (DEBUG)
-  verbose_message("debug level : %d\n", DEBUG);
-  #endif
-  atexit(&process_done);
+  #[cfg(not(windows))]
+  libc::atexit(process_done_atexit);
   process_setup_auto_thread_done();
+  process_setup_fork();
   stats_reset();
   os_init();
+  tld_register(heap_main.tld);
+  if option_is_enabled(option_purge_thread) { purge_thread_start(); }
 }
 
-fn process_done() {
-    // only shutdown if we were initialized
-    if (!process_is_initialized) {return;}
-    // ensure we are called once
-    static mut process_done: bool = false;
-    if (process_done) {return;}
-    process_done = true;
-
-    collect(true);
-    if (option_is_enabled(option_show_stats) ||
-        option_is_enabled(option_verbose)) {
-        stats_print(NULL);
-    }
-    verbose_message("process done: 0x%zx\n", heap_main.thread_id);
+/// List the blocks still allocated in the heap of the current thread and in
+/// the abandoned segments (those of threads that exited) as warnings, and
+/// return their totals. The heaps of other running threads are not visited
+/// as their pages may change while they are read.
+pub unsafe fn leaks_report() -> Leaks {
+  let mut leaks: Leaks = Leaks::new();
+  let heap: *mut Heap = get_default_heap();
+  if heap_is_initialized(heap) { _heap_leaks_collect((*(*heap).tld).heap_backing, &mut leaks); }
+  _segment_abandoned_visit(|segment| leaks.add_segment(segment));
+  leaks.print();
+  leaks
 }
 
+static process_done_called: AtomicBool = AtomicBool::new(false);
 
+unsafe fn process_done() {
+  // only shutdown if we were initialized
+  if !process_is_initialized { return; }
+  // ensure we are called once
+  if process_done_called.swap(true, Ordering::AcqRel) { return; }
 
-#if defined(_WIN32) && defined(SHARED_LIB)
-  // Windows DLL: easy to hook into process_init and thread_done
-  #include <windows.h>
+  purge_thread_done();
 
-  __declspec(dllexport) BOOL WINAPI DllMain(HINSTANCE inst, DWORD reason, LPVOID reserved) {
-    UNUSED(reserved);
-    UNUSED(inst);
-    if (reason==DLL_PROCESS_ATTACH) {
-      process_init();
-    }
-    else if (reason==DLL_THREAD_DETACH) {
-      thread_done();
-    }
-    return TRUE;
+  collect(true);
+  if option_is_enabled(option_show_stats) || option_is_enabled(option_verbose) {
+    stats_print(null_mut());
   }
-
-#elif defined(__cplusplus)
-  // C++: use static initialization to detect process start
-  static bool process_init(void) {
-    process_init();
-    return (heap_main.thread_id != 0);
+  if option_is_enabled(option_show_leaks) || option_is_enabled(option_show_stats) {
+    leaks_report();
   }
-  static bool initialized = process_init();
+  log::debug!("process done: {:#x}", heap_main.thread_id);
+}
 
-#elif defined(__GNUC__) || defined(__clang__)
-  // GCC,Clang: use the constructor attribute
-  static void __attribute__((constructor)) process_init(void) {
-    process_init();
-  }
+#[cfg(not(windows))]
+extern "C" fn process_done_atexit() {
+  unsafe { process_done(); }
+}
 
-#elif defined(_MSC_VER)
-  // MSVC: use data section magic for static libraries
-  // See <https://www.codeguru.com/cpp/misc/misc/applicationcontrol/article.php/c6945/Running-Code-Before-and-After-Main.htm>
-  static int process_init(void) {
-    process_init();
-    return 0;
-  }
-  typedef int(*_crt_cb)(void);
-  #ifdef _M_X64
-    __pragma(comment(linker, "/include:" "msvc_initu"))
-    #pragma section(".CRT$XIU", long, read)
-  #else
-    __pragma(comment(linker, "/include:" "_msvc_initu"))
-  #endif
-  #pragma data_seg(".CRT$XIU")
-  _crt_cb msvc_initu[] = { &process_init };
-  #pragma data_seg()
-
-#else
-#pragma message("define a way to call process_init/done on your platform")
-#endif
+// Use the constructor section to call `process_init` before `main`.
+// Allocations from constructors that run earlier go through `heap_main`,
+// which counts as initialized, so the allocation slow path (`_malloc_generic`)
+// calls `process_init` itself before their first segment is allocated.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+extern "C" fn process_init_ctor() {
+  unsafe { process_init(); }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[used]
+#[cfg_attr(target_os = "linux", link_section = ".init_array")]
+#[cfg_attr(target_os = "macos", link_section = "__DATA,__mod_init_func")]
+static process_init_array: extern "C" fn() = process_init_ctor;
+
+// TODO: Windows needs `DllMain` for a dynamic library (also calling `thread_done`
+// on `DLL_THREAD_DETACH`) or a `.CRT$XIU` entry for a static one
@@ -1,7 +1,16 @@
 use crate::types::*;
 use crate::init::*;
+#[cfg(feature = "secure")]
+use crate::options::_error_message;
+use crate::page_queue::_bin;
+#[cfg(feature = "oob-meta")]
+use crate::segment::_segment_meta_lookup;
 
-use core::mem::size_of;
+use core::{
+    mem::size_of,
+    ptr::{addr_of_mut, null_mut},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 // Overflow detecting multiply
 const MI_MUL_NO_OVERFLOW: usize = 1 << (4 * size_of::<usize>());  // sqrt(SIZE_MAX)
@@ -15,23 +24,22 @@ fn mul_overflow(size: usize, count: usize, total: &mut usize) -> bool {
 // Align a byte size to a size in _machine words_,
 // i.e. byte size == `wsize*sizeof(void*)`.
 #[inline]
-fn wsize_from_size(size: usize) -> usize {
+pub fn wsize_from_size(size: usize) -> usize {
   debug_assert!(size <= usize::max_value() - size_of::<usize>());
   (size + size_of::<usize>() - 1) / size_of::<usize>()
 }
 
 #[inline]
-fn get_default_heap() -> *mut Heap {
+pub unsafe fn get_default_heap() -> *mut Heap {
     // on some platforms, like macOS, the dynamic loader calls `malloc`
     // to initialize thread local data. To avoid recursion, we need to avoid
-    // accessing the thread local `_default_heap` until our module is loaded
+    // accessing the thread local default heap until our module is loaded
     // and use the statically allocated main heap until that time.
+    // On Linux other `.init_array` constructors may allocate before ours ran,
+    // and the thread local key does not exist before `process_init` anyway.
     // TODO: patch ourselves dynamically to avoid this check every time?
-    #[cfg(MI_TLS_RECURSE_GUARD)]
-    {
-        if !_process_is_initialized { return &_heap_main; }
-    }
-    return _heap_default;
+    if !process_is_initialized { return addr_of_mut!(heap_main); }
+    return _heap_default();
 }
 
 #[inline]
@@ -45,15 +53,15 @@ fn heap_is_backing(heap: *const Heap) -> bool {
 }
 
 #[inline]
-fn heap_is_initialized(heap: *mut Heap) -> bool {
+pub fn heap_is_initialized(heap: *mut Heap) -> bool {
     debug_assert!(heap != NULL);
-    heap != &_heap_empty
+    heap != addr_of_mut!(heap_empty)
 }
 
 #[inline]
-fn heap_get_free_small_page(heap: *mut Heap, size: usize) -> *mut Page {
+pub unsafe fn heap_get_free_small_page(heap: *mut Heap, size: usize) -> *mut Page {
     debug_assert!(size <= MI_SMALL_SIZE_MAX);
-    return (*heap).pages_free_direct[_wsize_from_size(size)];
+    return (*heap).pages_free_direct[wsize_from_size(size)];
 }
 
 // Get the page belonging to a certain size class
@@ -64,15 +72,40 @@ fn get_free_small_page(size: usize) -> *mut Page {
 
 
 // Segment that contains the pointer
+// (with `oob-meta` this is null if `p` is not in a segment)
 #[inline]
-fn ptr_segment(p: *const u8) -> *mut Segment {
+pub fn ptr_segment(p: *const u8) -> *mut Segment {
     // debug_assert!(p != NULL);
-    p as usize & !MI_SEGMENT_MASK as _
+    #[cfg(feature = "oob-meta")]
+    {
+        unsafe { _segment_meta_lookup(p as usize & !MI_SEGMENT_MASK) }
+    }
+    #[cfg(not(feature = "oob-meta"))]
+    {
+        (p as usize & !MI_SEGMENT_MASK) as _
+    }
+}
+
+// Start of the memory of a segment; the segment info is at the start unless it is kept out of band
+#[inline]
+pub unsafe fn segment_start(segment: *const Segment) -> *mut u8 {
+    #[cfg(feature = "oob-meta")]
+    {
+        (*segment).start
+    }
+    #[cfg(not(feature = "oob-meta"))]
+    {
+        segment as *mut u8
+    }
 }
 
 // Segment belonging to a page
 #[inline]
-fn page_segment(page: *const Page) -> *mut Segment {
+pub fn page_segment(page: *const Page) -> *mut Segment {
+    // with `oob-meta` the page array directly follows the out of band header
+    #[cfg(feature = "oob-meta")]
+    let segment: *mut Segment = unsafe { (page.sub((*page).segment_idx as usize) as *mut Segment).sub(1) };
+    #[cfg(not(feature = "oob-meta"))]
     let segment: *mut Segment = _ptr_segment(page);
     debug_assert!(segment == NULL || page == &segment->pages[page->segment_idx]);
     return segment;
@@ -80,9 +113,9 @@ fn page_segment(page: *const Page) -> *mut Segment {
 
 // Get the page containing the pointer
 #[inline]
-fn segment_page_of(segment: *const Segment, p: *const u8) -> *mut Page {
+pub fn segment_page_of(segment: *const Segment, p: *const u8) -> *mut Page {
     // if (segment->page_size > MI_SEGMENT_SIZE) return &segment->pages[0];  // huge pages
-    let diff: usize = (uint8_t*)p - (uint8_t*)segment;
+    let diff: usize = p as usize - unsafe { segment_start(segment) } as usize;
     debug_assert!(diff >= 0 && diff < MI_SEGMENT_SIZE);
     uintptr_t idx = (uintptr_t)diff >> segment->page_shift;
     debug_assert!(idx < segment->capacity);
@@ -92,7 +125,7 @@ fn segment_page_of(segment: *const Segment, p: *const u8) -> *mut Page {
 
 // Quick page start for initialized pages
 #[inline]
-fn page_start(segment: *const segment_t, page: *const page_t, page_size: *mut usize) -> *mut u8 {
+pub fn page_start(segment: *const segment_t, page: *const page_t, page_size: *mut usize) -> *mut u8 {
     return _segment_page_start(segment, page, (*page).block_size, page_size);
 }
 
@@ -104,7 +137,7 @@ fn ptr_page(p: *mut u8) -> *mut Page {
 
 // are all blocks in a page freed?
 #[inline]
-fn page_all_free(page: *const page_t) -> bool {
+pub fn page_all_free(page: *const page_t) -> bool {
     debug_assert!(page != NULL);
     return (page->used - page->thread_freed == 0);
 }
@@ -141,42 +174,88 @@ fn page_mostly_used(page: *const Page) -> bool {
 }
 
 #[inline]
-fn page_queue(heap: *mut Heap, size: usize) -> *mut PageQueue {
-    return &mut (*heap).pages[_bin(size)];
+pub fn page_queue(heap: *mut Heap, size: usize) -> *mut PageQueue {
+    return &mut (*heap).pages[_bin(size) as usize];
 }
 
 // -------------------------------------------------------------------
 // Encoding/Decoding the free list next pointers
 // -------------------------------------------------------------------
 
-fn block_nextx(cookie: usize, block: *mut Block) -> *mut Block {
-    #[cfg(MI_SECURE)]
+// With the `secure` feature the next pointers are encoded with the two
+// random keys of their page, so a buffer overflow cannot easily forge one.
+#[cfg(feature = "secure")]
+#[inline]
+fn ptr_encode(p: *const Block, keys: &[usize; 2]) -> usize {
+    (p as usize ^ keys[1]).rotate_left(keys[0] as u32).wrapping_add(keys[0])
+}
+
+#[cfg(feature = "secure")]
+#[inline]
+fn ptr_decode(x: usize, keys: &[usize; 2]) -> *mut Block {
+    (x.wrapping_sub(keys[0]).rotate_right(keys[0] as u32) ^ keys[1]) as *mut Block
+}
+
+// Are `p` and `q` blocks of the same page?
+#[inline]
+pub unsafe fn is_in_same_page(p: *const Block, q: *const Block) -> bool {
+    let segment: *mut Segment = ptr_segment(p as *const u8);
+    if ptr_segment(q as *const u8) != segment { return false; }
+    segment_page_of(segment, p as *const u8) == segment_page_of(segment, q as *const u8)
+}
+
+#[inline]
+pub unsafe fn block_nextx(page: *const Page, block: *const Block) -> *mut Block {
+    #[cfg(feature = "secure")]
     {
-        ((*block).next ^ cookie) as _
+        ptr_decode((*block).next, &(*page).keys)
     }
-    #[cfg(not(MI_SECURE))]
+    #[cfg(not(feature = "secure"))]
     {
+        let _ = page;
         (*block).next as _
     }
 }
 
-fn block_set_nextx(cookie: usize, block: *mut Block, next: *mut Block) {
-    #[cfg(MI_SECURE)]
+// A decoded pointer outside the page means the free list was corrupted;
+// report it and end the list there instead of following it.
+#[inline]
+pub unsafe fn block_next(page: *const Page, block: *const Block) -> *mut Block {
+    let next: *mut Block = block_nextx(page, block);
+    #[cfg(feature = "secure")]
+    {
+        if !next.is_null() && !is_in_same_page(block, next) {
+            _error_message(libc::EFAULT, format_args!("corrupted free list entry of size {}b at {:p}: value {:#x}", (*page).block_size, block, next as usize));
+            return null_mut();
+        }
+    }
+    next
+}
+
+#[inline]
+pub unsafe fn block_set_next(page: *const Page, block: *mut Block, next: *const Block) {
+    #[cfg(feature = "secure")]
     {
-        (*block).next = next as _ ^ cookie;
+        (*block).next = ptr_encode(next, &(*page).keys);
     }
-    #[cfg(not(MI_SECURE))]
+    #[cfg(not(feature = "secure"))]
     {
+        let _ = page;
         (*block).next = next as _;
     }
 }
 
-fn block_next(page: *mut Page, block: *mut Block) -> *mut Block {
-    block_nextx((*page).cookie, block)
+// Simple spin lock for the rarely contended slow paths
+#[inline]
+pub fn lock_acquire(lock: &AtomicBool) {
+    while lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        core::hint::spin_loop();
+    }
 }
 
-fn block_set_next(page: *mut Page, block: *mut Block, next: *mut Block) {
-    block_set_nextx((*page).cookie, block, next);
+#[inline]
+pub fn lock_release(lock: &AtomicBool) {
+    lock.store(false, Ordering::Release);
 }
 
 #[inline]
@@ -8,11 +8,41 @@ mod options;
 mod segment;
 mod internal;
 mod init;
+mod alloc;
+mod heap;
+mod page;
+mod page_queue;
+mod random;
+
+pub use alloc::expand;
+pub use heap::{check_heap, heap_check, heap_collect_abandon, heap_contains_block, heap_get_default, heap_visit_blocks, HeapArea, HeapCheck, Leaks};
+pub use init::{leaks_report, thread_done, thread_init};
+pub use options::{register_error, ErrorFun};
+pub use segment::is_in_heap_region;
+pub use types::Heap;
 
 pub struct Mimalloc;
 
-// TODO: Implement this
 unsafe impl GlobalAlloc for Mimalloc {
-    unsafe fn alloc(&self, _layout: Layout) -> *mut u8 { core::ptr::null_mut() }
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
-}
\ No newline at end of file
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // TODO: aligned allocation for larger alignments
+        if layout.align() > types::MI_MAX_ALIGN_SIZE { return core::ptr::null_mut(); }
+        alloc::_malloc(layout.size())
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) { alloc::_free(ptr) }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // huge blocks are remapped in place of copying
+        if layout.align() <= types::MI_MAX_ALIGN_SIZE {
+            let p = alloc::_realloc_huge(ptr, new_size);
+            if !p.is_null() { return p; }
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
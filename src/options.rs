@@ -1,20 +1,151 @@
+use core::{
+    ffi::{c_void, CStr},
+    fmt,
+    mem::transmute,
+    ptr::null_mut,
+    str,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicPtr, AtomicUsize, Ordering},
+};
+
 #[allow(non_camel_case_types)]
 pub enum Options {
     option_page_reset,
     option_cache_reset,
+    option_reset_delay,     // milliseconds a freed page or cached segment stays unused before it is reset
+    option_purge_thread,    // run a background thread that purges memory of idle threads after the reset delay
+    option_quarantine,      // number of freed blocks a heap holds back before reuse (`secure` feature only, 0 = off)
+    option_quarantine_size, // maximum total size in bytes of the blocks a heap holds back (0 = no limit)
+    option_guarded_sample_rate, // place one in every N allocations in front of a guard page (0 = off)
+    option_guarded_min,     // only sample allocations of at least this many bytes
+    option_guarded_max,     // only sample allocations of at most this many bytes (0 = no limit)
     option_pool_commit,
     option_large_os_pages,
     option_secure,
     option_show_stats,
+    option_show_leaks,      // list the blocks still allocated at process exit (also done with `option_show_stats`)
     option_show_errors,
     option_verbose,
 }
 pub use Options::*;
 
-pub fn option_is_enabled(_: Options) -> bool {
-    unimplemented!()
+/* -----------------------------------------------------------
+  Option values
+  Each option is read from the environment on first use, from
+  `MIMALLOC_` followed by its name in upper case (for example
+  `MIMALLOC_SECURE=2`). `1`, `true`, `yes` and `on` enable an
+  option, `0`, `false`, `no` and `off` disable it, and numbers
+  are taken as is. Anything else keeps the default.
+----------------------------------------------------------- */
+
+struct OptionDesc {
+    value: AtomicI64,
+    initialized: AtomicBool,
+    default: i64,
+    name: &'static str,  // in lower case, without the `mimalloc_` prefix
+}
+
+impl OptionDesc {
+    const fn new(default: i64, name: &'static str) -> OptionDesc {
+        OptionDesc { value: AtomicI64::new(default), initialized: AtomicBool::new(false), default, name }
+    }
+}
+
+// In the order of `Options`
+static options: [OptionDesc; option_verbose as usize + 1] = [
+    OptionDesc::new(0, "page_reset"),
+    OptionDesc::new(0, "cache_reset"),
+    OptionDesc::new(100, "reset_delay"),
+    OptionDesc::new(0, "purge_thread"),
+    OptionDesc::new(0, "quarantine"),
+    OptionDesc::new(0, "quarantine_size"),
+    OptionDesc::new(0, "guarded_sample_rate"),
+    OptionDesc::new(0, "guarded_min"),
+    OptionDesc::new(0, "guarded_max"),
+    OptionDesc::new(0, "pool_commit"),
+    OptionDesc::new(0, "large_os_pages"),
+    OptionDesc::new(cfg!(feature = "secure") as i64, "secure"),
+    OptionDesc::new(0, "show_stats"),
+    OptionDesc::new(0, "show_leaks"),
+    OptionDesc::new(cfg!(debug_assertions) as i64, "show_errors"),
+    OptionDesc::new(0, "verbose"),
+];
+
+// Read the value of an option from the environment, or its default if it is not set
+fn option_from_env(desc: &OptionDesc) -> i64 {
+    const PREFIX: &[u8] = b"MIMALLOC_";
+    let mut buf: [u8; 64] = [0; 64];  // the variable name, zero terminated
+    let name: &[u8] = desc.name.as_bytes();
+    if PREFIX.len() + name.len() >= buf.len() { return desc.default; }
+    buf[..PREFIX.len()].copy_from_slice(PREFIX);
+    for (i, c) in name.iter().enumerate() {
+        buf[PREFIX.len() + i] = c.to_ascii_uppercase();
+    }
+    let s: *const libc::c_char = unsafe { libc::getenv(buf.as_ptr() as *const libc::c_char) };
+    if s.is_null() { return desc.default; }
+    let value: &[u8] = unsafe { CStr::from_ptr(s) }.to_bytes();
+    if value.is_empty() { return desc.default; }
+    for on in &["1", "true", "yes", "on"] {
+        if value.eq_ignore_ascii_case(on.as_bytes()) { return 1; }
+    }
+    for off in &["0", "false", "no", "off"] {
+        if value.eq_ignore_ascii_case(off.as_bytes()) { return 0; }
+    }
+    match str::from_utf8(value).ok().and_then(|v| v.parse::<i64>().ok()) {
+        Some(n) => n,
+        None => {
+            let var: &str = str::from_utf8(&buf[..PREFIX.len() + name.len()]).unwrap_or("");
+            log::warn!("mimalloc: environment option {} has an invalid value", var);
+            desc.default
+        }
+    }
+}
+
+pub fn option_get(option: Options) -> u32 {
+    let desc: &OptionDesc = &options[option as usize];
+    if !desc.initialized.load(Ordering::Acquire) {
+        // racing threads read the same value
+        desc.value.store(option_from_env(desc), Ordering::Relaxed);
+        desc.initialized.store(true, Ordering::Release);
+    }
+    desc.value.load(Ordering::Relaxed).max(0) as u32
 }
 
-pub fn option_get(_: Options) -> u32 {
-    unimplemented!()
-}
\ No newline at end of file
+pub fn option_is_enabled(option: Options) -> bool {
+    option_get(option) != 0
+}
+
+/* -----------------------------------------------------------
+  Error messages
+----------------------------------------------------------- */
+
+// Called with an `errno` value (`EFAULT` for invalid pointers and double frees)
+// and the argument given at registration.
+pub type ErrorFun = fn(err: i32, arg: *mut c_void);
+
+static error_handler: AtomicUsize = AtomicUsize::new(0);  // an `ErrorFun`, or 0 if none
+static error_arg: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+
+/// Register a function that is called when an error such as an invalid or
+/// double free is detected. Pass `None` to go back to the default, which
+/// only reports the error (and aborts on `EFAULT` with the `secure` feature).
+pub fn register_error(fun: Option<ErrorFun>, arg: *mut c_void) {
+    error_arg.store(arg, Ordering::Release);
+    error_handler.store(fun.map_or(0, |f| f as usize), Ordering::Release);
+}
+
+pub fn _error_message(err: i32, args: fmt::Arguments) {
+    if option_is_enabled(option_show_errors) || option_is_enabled(option_verbose) {
+        log::error!("mimalloc: error: {}", args);
+    }
+    let handler: usize = error_handler.load(Ordering::Acquire);
+    if handler != 0 {
+        let fun: ErrorFun = unsafe { transmute::<usize, ErrorFun>(handler) };
+        fun(err, error_arg.load(Ordering::Acquire));
+    }
+    else {
+        #[cfg(feature = "secure")]
+        {
+            if err == libc::EFAULT { unsafe { libc::abort(); } }
+        }
+    }
+}
@@ -1,5 +1,7 @@
 #[cfg(not(windows))]
-use libc::{mmap, munmap, mprotect, madvise, MADV_DONTNEED, PROT_NONE, PROT_READ, PROT_WRITE};
+use libc::{mmap, munmap, mprotect, madvise, MADV_DONTNEED, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_NONE, PROT_READ, PROT_WRITE};
+#[cfg(target_os = "linux")]
+use libc::{mremap, MREMAP_FIXED, MREMAP_MAYMOVE};
 // #[cfg(windows)]
 use winapi::{
     shared::{
@@ -69,7 +71,7 @@ fn align_down_ptr(ptr: *mut u8, align: usize) -> *mut u8 {
 }
 
 // OS (small) page size
-unsafe fn os_page_size() -> usize {
+pub unsafe fn os_page_size() -> usize {
     _os_page_size
 }
 
@@ -156,7 +158,7 @@ unsafe fn os_init() {
     }
 }
 
-unsafe fn os_mem_free(addr: *mut u8, size: usize, stats: *mut Stats) -> bool {
+unsafe fn os_mem_free(addr: *mut u8, size: usize, was_committed: bool, stats: *mut Stats) -> bool {
     if addr.is_null() || size == 0 { return true; }
     let err: bool;
     #[cfg(windows)]
@@ -167,7 +169,7 @@ unsafe fn os_mem_free(addr: *mut u8, size: usize, stats: *mut Stats) -> bool {
     {
         err = munmap(addr, size) == -1;
     }
-    _stat_decrease(&mut (*stats).committed, size as _); // TODO: what if never committed?
+    if was_committed { _stat_decrease(&mut (*stats).committed, size as _); }
     _stat_decrease(&mut (*stats).reserved, size as _);
     if err {
         warn!("munmap failed: {}, addr {:08x}, size {}", errno::errno(), addr as usize, size);
@@ -293,7 +295,7 @@ unsafe fn os_mem_alloc_aligned(mut size: usize, align: usize, commit: bool, stat
 
     // if not aligned, free it, overallocate, and unmap around it
     if p as usize % align != 0 {
-        os_mem_free(p, size, stats);
+        os_mem_free(p, size, commit, stats);
         if size >= (usize::max_value() - align) { return null_mut(); } // overflow
         let over_size: usize = size + align;
 
@@ -316,12 +318,12 @@ unsafe fn os_mem_alloc_aligned(mut size: usize, align: usize, commit: bool, stat
                     break;
                 } else {
                     // otherwise free and allocate at an aligned address in there
-                    os_mem_free(p, over_size, stats);
+                    os_mem_free(p, over_size, commit, stats);
                     let aligned_p = align_up_ptr(p, align);
                     p = win_virtual_alloc(aligned_p, size, align, flags);
                     if p == aligned_p { break; } // success!
                     if !p.is_null() { // should not happen?
-                        os_mem_free(p, size, stats);  
+                        os_mem_free(p, size, commit, stats);  
                         p = null_mut();
                     }
                 }
@@ -338,8 +340,8 @@ unsafe fn os_mem_alloc_aligned(mut size: usize, align: usize, commit: bool, stat
             let mid_size: usize = align_up(size, os_page_size());
             let post_size: usize = over_size - pre_size - mid_size;
             debug_assert!(pre_size < over_size && post_size < over_size && mid_size >= size);
-            if pre_size > 0  { os_mem_free(p, pre_size, stats); }
-            if post_size > 0 { os_mem_free((aligned_p as usize + mid_size) as *mut u8, post_size, stats); }
+            if pre_size > 0  { os_mem_free(p, pre_size, commit, stats); }
+            if post_size > 0 { os_mem_free((aligned_p as usize + mid_size) as *mut u8, post_size, commit, stats); }
             // we can return the aligned pointer on `mmap` systems
             p = aligned_p;
         }
@@ -358,7 +360,14 @@ pub unsafe fn _os_alloc(mut size: usize, stats: *mut Stats) -> *mut u8 {
 pub unsafe fn _os_free(p: *mut u8, mut size: usize, stats: *mut Stats) {
   if size == 0 || p.is_null() { return; }
   size = os_good_alloc_size(size, 0);
-  os_mem_free(p, size, stats);
+  os_mem_free(p, size, true, stats);
+}
+
+// Free memory that may not be (fully) committed; the caller accounts for the committed part.
+pub unsafe fn _os_free_ex(p: *mut u8, mut size: usize, was_committed: bool, stats: *mut Stats) {
+  if size == 0 || p.is_null() { return; }
+  size = os_good_alloc_size(size, 0);
+  os_mem_free(p, size, was_committed, stats);
 }
 
 pub unsafe fn _os_alloc_aligned(mut size: usize, mut align: usize, commit: bool, tld: *mut OsTld) -> *mut u8 {
@@ -368,6 +377,78 @@ pub unsafe fn _os_alloc_aligned(mut size: usize, mut align: usize, commit: bool,
   return os_mem_alloc_aligned(size, align, commit, (*tld).stats);
 }
 
+// Resize an OS allocation, moving it if needed while keeping it aligned to `align`.
+// Returns the (possibly new) address, or null if the remap failed, in which
+// case the original allocation at `p` is left untouched.
+#[cfg(target_os = "linux")]
+pub unsafe fn _os_remap(p: *mut u8, oldsize: usize, newsize: usize, align: usize, stats: *mut Stats) -> *mut u8 {
+    debug_assert!(!p.is_null() && (p as usize % align) == 0);
+    debug_assert!((oldsize % os_page_size()) == 0 && (newsize % os_page_size()) == 0);
+    if p.is_null() || oldsize == 0 || newsize == 0 { return null_mut(); }
+    let diff = newsize as i64 - oldsize as i64;
+
+    // first try to resize in place
+    let mut q = mremap(p as _, oldsize, newsize, 0);
+    if q != MAP_FAILED {
+        _stat_increase(&mut (*stats).mmap_calls, 1);
+    }
+    else {
+        // otherwise reserve an aligned range (which counts the call) and move the mapping on top of it
+        let target = os_mem_alloc_aligned(newsize, align, false, stats);
+        if target.is_null() { return null_mut(); }
+        q = mremap(p as _, oldsize, newsize, MREMAP_MAYMOVE | MREMAP_FIXED, target);
+        if q == MAP_FAILED {
+            warn!("mremap failed: {}, addr {:08x}, size {}, new size {}", errno::errno(), p as usize, oldsize, newsize);
+            os_mem_free(target, newsize, false, stats);
+            return null_mut();
+        }
+        debug_assert!(q as *mut u8 == target);
+        // the reservation is replaced by the moved mapping and the old range is gone
+        _stat_decrease(&mut (*stats).reserved, newsize as _);
+    }
+    _stat_update(&mut (*stats).reserved, diff);
+    _stat_update(&mut (*stats).committed, diff);
+    q as _
+}
+
+// Grow an OS allocation in place by extending it into the adjoining virtual range.
+// Returns `false` if that range is not available.
+pub unsafe fn _os_expand(p: *mut u8, oldsize: usize, newsize: usize, stats: *mut Stats) -> bool {
+    debug_assert!(!p.is_null() && newsize >= oldsize);
+    debug_assert!((oldsize % os_page_size()) == 0 && (newsize % os_page_size()) == 0);
+    if p.is_null() || newsize < oldsize { return false; }
+    if newsize == oldsize { return true; }
+    let ok: bool;
+    #[cfg(target_os = "linux")]
+    {
+        ok = mremap(p as _, oldsize, newsize, 0) != MAP_FAILED;
+    }
+    #[cfg(all(not(windows), not(target_os = "linux")))]
+    {
+        // map right behind the allocation; the address is only a hint so check we got it
+        let next: *mut u8 = p.add(oldsize);
+        let q = mmap(next as _, newsize - oldsize, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+        ok = q as *mut u8 == next;
+        if !ok && q != MAP_FAILED { munmap(q, newsize - oldsize); }
+    }
+    #[cfg(windows)]
+    {
+        // a separate reservation could not be released together with the original one
+        ok = false;
+    }
+    if ok {
+        _stat_update(&mut (*stats).reserved, (newsize - oldsize) as _);
+        _stat_update(&mut (*stats).committed, (newsize - oldsize) as _);
+    }
+    ok
+}
+
+#[cfg(not(target_os = "linux"))]
+pub unsafe fn _os_remap(_p: *mut u8, _oldsize: usize, _newsize: usize, _align: usize, _stats: *mut Stats) -> *mut u8 {
+    // not supported; the caller falls back to allocate and copy
+    null_mut()
+}
+
 /* -----------------------------------------------------------
   OS memory API: reset, commit, decommit, protect, unprotect.
 ----------------------------------------------------------- */
@@ -454,6 +535,10 @@ unsafe fn os_reset(addr: *mut u8, size: usize, stats: *mut Stats) -> bool {
     }
 }
 
+pub unsafe fn _os_reset(addr: *mut u8, size: usize, stats: *mut Stats) -> bool {
+    os_reset(addr, size, stats)
+}
+
 // Protect a region in memory to be not accessible.
 unsafe fn os_protectx(addr: *mut u8, size: usize, protect: bool) -> bool {
     // page align conservatively within the range
@@ -486,6 +571,14 @@ unsafe fn os_unprotect(addr: *mut u8, size: usize) -> bool {
     os_protectx(addr, size, false)
 }
 
+pub unsafe fn _os_protect(addr: *mut u8, size: usize) -> bool {
+    os_protect(addr, size)
+}
+
+pub unsafe fn _os_unprotect(addr: *mut u8, size: usize) -> bool {
+    os_unprotect(addr, size)
+}
+
 // Commit/Decommit memory. Commit is aligned liberal, while decommit is aligned conservative.
 unsafe fn os_commitx(addr: *mut u8, size: usize, commit: bool, stats: *mut Stats) -> bool {
     // page align in the range, commit liberally, decommit conservative
@@ -512,6 +605,8 @@ unsafe fn os_commitx(addr: *mut u8, size: usize, commit: bool, stats: *mut Stats
     }
     #[cfg(not(windows))]
     {
+        // release the physical pages too so a decommit actually lowers the resident size
+        if !commit { madvise(start as _, csize, MADV_DONTNEED); }
         err = mprotect(start, csize, if commit { PROT_READ | PROT_WRITE } else { PROT_NONE });
     }
     if err != 0 {
@@ -529,7 +624,15 @@ unsafe fn os_decommit(addr: *mut u8, size: usize, stats: *mut Stats) -> bool {
     os_commitx(addr, size, false, stats)
 }
 
-unsafe fn _mi_os_shrink(p: *mut u8, oldsize: usize, newsize: usize, stats: *mut Stats) -> bool {
+pub unsafe fn _os_commit(addr: *mut u8, size: usize, stats: *mut Stats) -> bool {
+    os_commit(addr, size, stats)
+}
+
+pub unsafe fn _os_decommit(addr: *mut u8, size: usize, stats: *mut Stats) -> bool {
+    os_decommit(addr, size, stats)
+}
+
+pub unsafe fn _os_shrink(p: *mut u8, oldsize: usize, newsize: usize, was_committed: bool, stats: *mut Stats) -> bool {
     // page align conservatively within the range
     debug_assert!(oldsize > newsize && !p.is_null());
     if oldsize < newsize || p.is_null() { return false; }
@@ -544,10 +647,10 @@ unsafe fn _mi_os_shrink(p: *mut u8, oldsize: usize, newsize: usize, stats: *mut
     #[cfg(windows)]
     {
         // we cannot shrink on windows, but we can decommit
-        os_decommit(start, size, stats)
+        if was_committed { os_decommit(start, size, stats) } else { true }
     }
     #[cfg(not(windows))]
     {
-        os_mem_free(start, size, stats)
+        os_mem_free(start, size, was_committed, stats)
     }
 }
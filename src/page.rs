@@ -0,0 +1,371 @@
+use crate::{alloc::*, init::*, internal::*, os::*, page_queue::*, random::*, segment::*, stats::*, types::*};
+
+use core::{ptr::null_mut, sync::atomic::Ordering};
+
+/* -----------------------------------------------------------
+  The page queues and abandoned pages
+----------------------------------------------------------- */
+
+// The queue of a page in `heap`. Guarded pages are kept in the huge
+// queue, whatever their block size, so they are never searched for free blocks.
+pub unsafe fn _page_queue_of(heap: *mut Heap, page: *const Page) -> *mut PageQueue {
+    if (*page_segment(page)).is_guarded {
+        &mut (*heap).pages[MI_BIN_HUGE]
+    }
+    else {
+        page_queue(heap, (*page).block_size)
+    }
+}
+
+// Take over a page of an abandoned segment: it joins the queue
+// for its block size in `heap`. Its thread free list is collected
+// like for any other page once we search it for free blocks.
+pub unsafe fn _page_reclaim(heap: *mut Heap, page: *mut Page) {
+    debug_assert!((*page).heap.is_null());
+    let pq: *mut PageQueue = _page_queue_of(heap, page);
+    page_queue_push(heap, pq, page);
+}
+
+// Abandon a page that still has blocks in use; another thread can reclaim it.
+pub unsafe fn _page_abandon(page: *mut Page, pq: *mut PageQueue) {
+    let segments_tld: *mut SegmentsTld = &mut (*(*(*page).heap).tld).segments;
+    page_queue_remove(pq, page);
+    _segment_page_abandon(page, segments_tld);
+}
+
+// Free a page with no more blocks in use
+pub unsafe fn _page_free(page: *mut Page, pq: *mut PageQueue, force: bool) {
+    debug_assert!(page_all_free(page));
+    let segments_tld: *mut SegmentsTld = &mut (*(*(*page).heap).tld).segments;
+    page_queue_remove(pq, page);
+    _segment_page_free(page, force, segments_tld);
+}
+
+// Retire a page without blocks in use. We keep it if it is the only page
+// of its size, as it would likely be allocated again right away
+// (but never a guarded page, its block cannot be reused).
+pub unsafe fn _page_retire(page: *mut Page) {
+    debug_assert!(page_all_free(page));
+    let pq: *mut PageQueue = _page_queue_of((*page).heap, page);
+    if (*pq).first == page && (*pq).last == page && !(*page_segment(page)).is_guarded { return; }
+    _page_free(page, pq, false);
+}
+
+/* -----------------------------------------------------------
+  Collect the blocks freed in the meantime
+----------------------------------------------------------- */
+
+// Move the blocks freed by other threads to the free list
+unsafe fn page_thread_free_collect(page: *mut Page) {
+    // take the list, keeping the delayed free flags in the bottom 2 bits
+    let mut tfree: usize = (*page).thread_free.value.load(Ordering::Relaxed);
+    loop {
+        if tfree & !3 == 0 { return; }
+        match (*page).thread_free.value.compare_exchange_weak(tfree, tfree & 3, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(current) => tfree = current,
+        }
+    }
+    let head: *mut Block = (tfree & !3) as *mut Block;
+
+    // find the tail
+    let mut count: usize = 1;
+    let mut tail: *mut Block = head;
+    loop {
+        let next: *mut Block = block_next(page, tail);
+        if next.is_null() { break; }
+        count += 1;
+        tail = next;
+    }
+
+    // and prepend to the free list
+    block_set_next(page, tail, (*page).free);
+    (*page).free = head;
+
+    // update counts now
+    (*page).thread_freed.fetch_sub(count, Ordering::Relaxed);
+    (*page).used -= count;
+}
+
+pub unsafe fn _page_free_collect(page: *mut Page) {
+    // free the local free list
+    if !(*page).local_free.is_null() {
+        if (*page).free.is_null() {
+            // usual case
+            (*page).free = (*page).local_free;
+        }
+        else {
+            let mut tail: *mut Block = (*page).local_free;
+            loop {
+                let next: *mut Block = block_next(page, tail);
+                if next.is_null() { break; }
+                tail = next;
+            }
+            block_set_next(page, tail, (*page).free);
+            (*page).free = (*page).local_free;
+        }
+        (*page).local_free = null_mut();
+    }
+    // and the thread free list
+    page_thread_free_collect(page);
+}
+
+/* -----------------------------------------------------------
+  Extend the free list of a page
+----------------------------------------------------------- */
+
+const MI_MAX_EXTEND_SIZE: usize = 4*1024;   // heuristic, one OS page seems to work well.
+const MI_MIN_EXTEND: usize = 1;
+
+// Link `extend` fresh blocks after the current capacity in front of the free list.
+unsafe fn page_free_list_extend(page: *mut Page, extend: usize) {
+    debug_assert!((*page).free.is_null());
+    debug_assert!(extend > 0);
+    let bsize: usize = (*page).block_size;
+    let page_area: *mut u8 = page_start(page_segment(page), page, null_mut());
+    let start: *mut Block = page_area.add((*page).capacity as usize * bsize) as *mut Block;
+    let mut block: *mut Block = start;
+    for _ in 1..extend {
+        let next: *mut Block = (block as *mut u8).add(bsize) as *mut Block;
+        block_set_next(page, block, next);
+        block = next;
+    }
+    block_set_next(page, block, null_mut());
+    (*page).free = start;
+}
+
+// With `random-free-list` (part of `secure`) the new blocks are linked in a
+// random order, so the address of the next allocation cannot be predicted.
+// The blocks are divided in up to `MI_MAX_SLICES` slices and the list randomly
+// alternates between them; as we extend at most `MI_MAX_EXTEND_SIZE` at a time,
+// the blocks stay close together.
+#[cfg(feature = "random-free-list")]
+const MI_MAX_SLICE_SHIFT: usize = 6;   // at most 64 slices
+#[cfg(feature = "random-free-list")]
+const MI_MAX_SLICES: usize = 1 << MI_MAX_SLICE_SHIFT;
+#[cfg(feature = "random-free-list")]
+const MI_MIN_SLICES: usize = 2;
+
+#[cfg(feature = "random-free-list")]
+unsafe fn page_free_list_extend_secure(heap: *mut Heap, page: *mut Page, extend: usize) {
+    debug_assert!((*page).free.is_null());
+    debug_assert!(extend >= MI_MIN_SLICES);
+    let bsize: usize = (*page).block_size;
+    let page_area: *mut u8 = page_start(page_segment(page), page, null_mut());
+
+    // set up `slice_count` slices to alternate between
+    let mut shift: usize = MI_MAX_SLICE_SHIFT;
+    while (extend >> shift) == 0 { shift -= 1; }
+    let slice_count: usize = 1 << shift;
+    let slice_extend: usize = extend / slice_count;
+    let mut blocks: [*mut Block; MI_MAX_SLICES] = [null_mut(); MI_MAX_SLICES];  // current start of the slice
+    let mut counts: [usize; MI_MAX_SLICES] = [0; MI_MAX_SLICES];                // available blocks in the slice
+    for i in 0..slice_count {
+        blocks[i] = page_area.add(((*page).capacity as usize + i*slice_extend) * bsize) as *mut Block;
+        counts[i] = slice_extend;
+    }
+    counts[slice_count - 1] += extend % slice_count;  // final slice holds the modulus too
+
+    // and initialize the free list by randomly threading through them
+    let r: usize = heap_random(heap);
+    let mut current: usize = r % slice_count;
+    counts[current] -= 1;
+    let free_start: *mut Block = blocks[current];
+    // and iterate through the rest; only shuffle once for every byte of `rnd`
+    let mut rnd: usize = random_shuffle(r | 1);  // ensure not 0
+    for i in 1..extend {
+        let round: usize = i % MI_INTPTR_SIZE;
+        if round == 0 { rnd = random_shuffle(rnd); }
+        // select a random next slice index that still has blocks
+        let mut next: usize = (rnd >> (8*round)) & (slice_count - 1);
+        while counts[next] == 0 {
+            next += 1;
+            if next == slice_count { next = 0; }
+        }
+        // and link the current block to it
+        counts[next] -= 1;
+        let block: *mut Block = blocks[current];
+        blocks[current] = (block as *mut u8).add(bsize) as *mut Block;  // bump to the following block
+        block_set_next(page, block, blocks[next]);  // note: we may have `current == next`
+        current = next;
+    }
+    block_set_next(page, blocks[current], null_mut());  // end of the list
+    (*page).free = free_start;
+}
+
+// Extend the capacity (up to reserved) by initializing a free list.
+// We do at most `MI_MAX_EXTEND` to avoid touching too much memory.
+unsafe fn page_extend_free(heap: *mut Heap, page: *mut Page, stats: *mut Stats) {
+    if !(*page).free.is_null() { return; }
+    if (*page).capacity >= (*page).reserved { return; }
+
+    let mut page_size: usize = 0;
+    page_start(page_segment(page), page, &mut page_size);
+    if (*page).is_reset {
+        (*page).is_reset = false;
+        _stat_decrease(&mut (*stats).reset, page_size as _);
+    }
+    _stat_increase(&mut (*stats).pages_extended, 1);
+
+    // calculate the extend count
+    let mut extend: usize = ((*page).reserved - (*page).capacity) as usize;
+    let max_extend: usize = core::cmp::max(MI_MAX_EXTEND_SIZE / (*page).block_size, MI_MIN_EXTEND);
+    if extend > max_extend { extend = max_extend; }
+
+    #[cfg(feature = "random-free-list")]
+    {
+        if extend >= MI_MIN_SLICES {
+            page_free_list_extend_secure(heap, page, extend);
+        }
+        else {
+            page_free_list_extend(page, extend);
+        }
+    }
+    #[cfg(not(feature = "random-free-list"))]
+    {
+        let _ = heap;
+        page_free_list_extend(page, extend);
+    }
+    (*page).capacity += extend as u16;
+}
+
+// Initialize a fresh page
+unsafe fn page_init(heap: *mut Heap, page: *mut Page, block_size: usize, stats: *mut Stats) {
+    debug_assert!(block_size > 0);
+    let segment: *mut Segment = page_segment(page);
+    (*page).block_size = block_size;
+    let mut page_size: usize = 0;
+    _segment_page_start(segment, page, block_size, &mut page_size);
+    (*page).reserved = (page_size / block_size) as u16;
+    #[cfg(feature = "secure")]
+    {
+        (*page).keys = [heap_random(heap), heap_random(heap)];
+    }
+    page_extend_free(heap, page, stats);
+}
+
+/* -----------------------------------------------------------
+  Get a fresh page to use
+----------------------------------------------------------- */
+
+// allocate a fresh page from a segment
+unsafe fn page_fresh_alloc(heap: *mut Heap, pq: *mut PageQueue, block_size: usize) -> *mut Page {
+    let tld: *mut Tld = (*heap).tld;
+    let page: *mut Page = _segment_page_alloc(block_size, &mut (*tld).segments, &mut (*tld).os);
+    if page.is_null() { return null_mut(); }
+    page_init(heap, page, block_size, &mut (*tld).stats);
+    _stat_increase(&mut (*tld).stats.pages, 1);
+    page_queue_push(heap, pq, page);
+    page
+}
+
+// Get a fresh page to use; abandoned pages of exited threads go first.
+unsafe fn page_fresh(heap: *mut Heap, pq: *mut PageQueue) -> *mut Page {
+    let page: *mut Page = (*pq).first;
+    if !(*heap).no_reclaim &&
+        _segment_try_reclaim_abandoned(heap, false, &mut (*(*heap).tld).segments) &&
+        page != (*pq).first
+    {
+        // we reclaimed, and we got lucky with a reclaimed page in our queue
+        let page: *mut Page = (*pq).first;
+        if !(*page).free.is_null() { return page; }
+    }
+    // otherwise allocate the page
+    page_fresh_alloc(heap, pq, (*pq).block_size)
+}
+
+/* -----------------------------------------------------------
+  Find pages with free blocks
+----------------------------------------------------------- */
+
+// Find a page with free blocks in `pq`, collecting and extending
+// pages on the way, or get a fresh page if there is none.
+unsafe fn page_queue_find_free_ex(heap: *mut Heap, pq: *mut PageQueue) -> *mut Page {
+    let mut page: *mut Page = (*pq).first;
+    while !page.is_null() {
+        let next: *mut Page = (*page).next;
+        _page_free_collect(page);
+        if (*page).free.is_null() {
+            page_extend_free(heap, page, &mut (*(*heap).tld).stats);
+        }
+        if !(*page).free.is_null() { return page; }
+        page = next;
+    }
+    page_fresh(heap, pq)
+}
+
+// Huge blocks get a page (and segment) of their own
+unsafe fn huge_page_alloc(heap: *mut Heap, size: usize) -> *mut Page {
+    let block_size: usize = (size + MI_INTPTR_SIZE - 1) & !(MI_INTPTR_SIZE - 1);
+    let pq: *mut PageQueue = page_queue(heap, block_size);
+    let page: *mut Page = page_fresh_alloc(heap, pq, block_size);
+    if !page.is_null() {
+        _stat_increase(&mut (*(*heap).tld).stats.huge, block_size as _);
+    }
+    page
+}
+
+// Allocate a page with a single block of at least `size` bytes in a segment of its own.
+// The block ends in a guard page at an OS page boundary, which is protected here;
+// returns null if that fails.
+pub unsafe fn _page_guarded_alloc(heap: *mut Heap, size: usize) -> *mut Page {
+    let tld: *mut Tld = (*heap).tld;
+    let psize: usize = os_page_size();
+    // the page start need not be OS page aligned: reserve up to a page more in front of the guard
+    let page: *mut Page = _segment_guarded_page_alloc(size + 2*psize, &mut (*tld).segments, &mut (*tld).os);
+    if page.is_null() { return null_mut(); }
+    let start: usize = _segment_page_start(page_segment(page), page, 0, null_mut()) as usize;
+    let guard: usize = (start + size + psize - 1) & !(psize - 1);
+    page_init(heap, page, guard + psize - start, &mut (*tld).stats);
+    _stat_increase(&mut (*tld).stats.pages, 1);
+    let pq: *mut PageQueue = &mut (*heap).pages[MI_BIN_HUGE];
+    page_queue_push(heap, pq, page);
+    if !_os_protect(guard as *mut u8, psize) {
+        _page_free(page, pq, true);
+        return null_mut();
+    }
+    page
+}
+
+// Free the guarded pages of `heap` whose block was freed by another thread.
+// Those are in the huge queue, which is never searched for free blocks.
+pub unsafe fn _page_guarded_collect(heap: *mut Heap) {
+    let pq: *mut PageQueue = &mut (*heap).pages[MI_BIN_HUGE];
+    let mut page: *mut Page = (*pq).first;
+    while !page.is_null() {
+        let next: *mut Page = (*page).next;
+        if (*page_segment(page)).is_guarded {
+            _page_free_collect(page);
+            if page_all_free(page) { _page_free(page, pq, false); }
+        }
+        page = next;
+    }
+}
+
+// Generic allocation routine if the fast path (`_page_malloc`) does not succeed.
+pub unsafe fn _malloc_generic(mut heap: *mut Heap, size: usize) -> *mut u8 {
+    // initialize if necessary; the process is set up before the first segment
+    // is allocated so the cookie of `heap_main` never changes afterwards
+    if !process_is_initialized {
+        process_init();
+        heap = get_default_heap();
+    }
+    if !heap_is_initialized(heap) {
+        thread_init();
+        heap = get_default_heap();
+    }
+    _purge_thread_check();
+
+    // find (or allocate) a page with free blocks
+    let page: *mut Page = if size <= MI_LARGE_SIZE_MAX {
+        page_queue_find_free_ex(heap, page_queue(heap, size))
+    }
+    else {
+        huge_page_alloc(heap, size)
+    };
+    if page.is_null() { return null_mut(); }  // out of memory
+
+    // and try again, this time succeeding
+    debug_assert!(!(*page).free.is_null());
+    _page_malloc(heap, page, size)
+}
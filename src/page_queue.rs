@@ -0,0 +1,117 @@
+use crate::{init::*, internal::*, types::*};
+
+use core::ptr::{addr_of_mut, null_mut};
+
+/* -----------------------------------------------------------
+  Definition of page queues for each block size
+----------------------------------------------------------- */
+
+// Bit scan reverse: return the index of the highest bit.
+#[inline]
+fn bsr32(x: u32) -> u8 {
+    (31 - x.leading_zeros()) as u8
+}
+
+// Return the bin for a given field size.
+// Returns MI_BIN_HUGE if the size is too large.
+// We use `wsize` for the size in "machine word sizes",
+// i.e. byte size == `wsize*sizeof(void*)`.
+pub fn _bin(size: usize) -> u8 {
+    let mut wsize: usize = wsize_from_size(size);
+    if wsize <= 1 {
+        1
+    }
+    else if wsize <= 8 {
+        wsize as u8
+    }
+    else if wsize > MI_LARGE_WSIZE_MAX {
+        MI_BIN_HUGE as u8
+    }
+    else {
+        wsize -= 1;
+        // find the highest bit
+        let b: u8 = bsr32(wsize as u32);  // note: wsize != 0
+        // and use the top 3 bits to determine the bin (~16% worst internal waste)
+        // - adjust with 3 because we use do not round the first 8 sizes
+        //   which each get an exact bin
+        ((b << 2) + ((wsize >> (b - 2)) & 0x03) as u8) - 3
+    }
+}
+
+/* -----------------------------------------------------------
+  Queue of pages with free blocks
+----------------------------------------------------------- */
+
+// The current small page array is for efficiency and for each
+// small size (up to 256) it points directly to the page for that
+// size without having to compute the bin. This means when the
+// current free page queue is updated for a small bin, we need to update a
+// range of entries in `heap.pages_free_direct`.
+unsafe fn heap_queue_first_update(heap: *mut Heap, pq: *const PageQueue) {
+    let size: usize = (*pq).block_size;
+    if size > MI_SMALL_SIZE_MAX { return; }
+
+    let mut page: *mut Page = (*pq).first;
+    if page.is_null() { page = addr_of_mut!(page_empty); }
+
+    // find index in the right direct page array
+    let idx: usize = wsize_from_size(size);
+    let pages_free = &mut (*heap).pages_free_direct;
+    if pages_free[idx] == page { return; }  // already set
+
+    // find start slot
+    let start: usize = if idx <= 1 {
+        0
+    }
+    else {
+        // find previous size; due to minimal alignment upto 3 previous bins may need to be skipped
+        let bin: u8 = _bin(size);
+        let mut prev: *const PageQueue = pq.sub(1);
+        while bin == _bin((*prev).block_size) && prev > (*heap).pages.as_ptr() {
+            prev = prev.sub(1);
+        }
+        core::cmp::min(1 + wsize_from_size((*prev).block_size), idx)
+    };
+
+    // set size range to the right page
+    for sz in start..=idx {
+        pages_free[sz] = page;
+    }
+}
+
+pub unsafe fn page_queue_push(heap: *mut Heap, queue: *mut PageQueue, page: *mut Page) {
+    debug_assert!((*page).heap.is_null());
+    (*page).heap = heap;
+    (*page).next = (*queue).first;
+    (*page).prev = null_mut();
+    if !(*queue).first.is_null() {
+        debug_assert!((*(*queue).first).prev.is_null());
+        (*(*queue).first).prev = page;
+        (*queue).first = page;
+    }
+    else {
+        (*queue).first = page;
+        (*queue).last = page;
+    }
+
+    // update direct
+    heap_queue_first_update(heap, queue);
+    (*heap).page_count += 1;
+}
+
+pub unsafe fn page_queue_remove(queue: *mut PageQueue, page: *mut Page) {
+    debug_assert!(!page.is_null());
+    if !(*page).prev.is_null() { (*(*page).prev).next = (*page).next; }
+    if !(*page).next.is_null() { (*(*page).next).prev = (*page).prev; }
+    if page == (*queue).last { (*queue).last = (*page).prev; }
+    let heap: *mut Heap = (*page).heap;
+    if page == (*queue).first {
+        (*queue).first = (*page).next;
+        // update first
+        heap_queue_first_update(heap, queue);
+    }
+    (*heap).page_count -= 1;
+    (*page).next = null_mut();
+    (*page).prev = null_mut();
+    (*page).heap = null_mut();
+}
@@ -0,0 +1,87 @@
+use crate::{stats::*, types::*};
+
+use core::mem::size_of;
+
+/* -----------------------------------------------------------
+  Random numbers
+  The seeds come from the OS so cookies and free list keys
+  cannot be guessed; after that we only shuffle.
+----------------------------------------------------------- */
+
+#[cfg(target_pointer_width = "64")]
+pub fn random_shuffle(mut x: usize) -> usize {
+    // by Sebastiano Vigna, see: <http://xoshiro.di.unimi.it/splitmix64.c>
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    x
+}
+
+#[cfg(target_pointer_width = "32")]
+pub fn random_shuffle(mut x: usize) -> usize {
+    // by Chris Wellons, see: <https://nullprogram.com/blog/2018/07/31/>
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+// Fill `buf` with random bytes from the OS; returns `false` if none are available.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn os_random_buf(buf: &mut [u8]) -> bool {
+    let mut filled: usize = 0;
+    while filled < buf.len() {
+        let n = unsafe {
+            libc::syscall(libc::SYS_getrandom, buf[filled..].as_mut_ptr(), buf.len() - filled, libc::GRND_NONBLOCK)
+        };
+        if n < 0 {
+            if errno::errno().0 == libc::EINTR { continue; }
+            return false;
+        }
+        filled += n as usize;
+    }
+    true
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+fn os_random_buf(buf: &mut [u8]) -> bool {
+    unsafe { libc::arc4random_buf(buf.as_mut_ptr() as *mut libc::c_void, buf.len()); }
+    true
+}
+
+#[cfg(windows)]
+fn os_random_buf(buf: &mut [u8]) -> bool {
+    unsafe { winapi::um::ntsecapi::RtlGenRandom(buf.as_mut_ptr() as *mut _, buf.len() as u32) != 0 }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "openbsd", target_os = "netbsd")))]
+fn os_random_buf(_buf: &mut [u8]) -> bool {
+    false
+}
+
+pub fn random_init(seed: usize) -> usize {
+    let mut buf = [0u8; size_of::<usize>()];
+    let mut x: usize = if os_random_buf(&mut buf) {
+        usize::from_ne_bytes(buf)
+    }
+    else {
+        // fall back on the clock and address space randomization
+        (random_init as usize) ^ (_clock_now() as usize) ^ (&buf as *const _ as usize)
+    };
+    x ^= seed;
+    for _ in 0..(x % 16) {
+        x = random_shuffle(x);
+    }
+    random_shuffle(x)
+}
+
+pub unsafe fn heap_random(heap: *mut Heap) -> usize {
+    let r: usize = (*heap).random;
+    (*heap).random = random_shuffle(r);
+    r
+}
@@ -1,8 +1,8 @@
-use crate::types::*;
+use crate::{init::*, internal::*, options::*, os::*, page::*, stats::*, types::*};
 
 use core::{
-    ptr::null_mut,
-    sync::atomic::{AtomicPtr, AtomicUsize},
+    ptr::{addr_of_mut, null_mut},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
 };
 
 const PAGE_HUGE_ALIGN: usize = 256*1024;
@@ -112,13 +112,13 @@ unsafe fn segment_queue_insert_before(queue: *mut SegmentQueue, elem: *mut Segme
 
 
 // Start of the page available memory; can be used on uninitialized pages (only `segment_idx` must be set)
-unsafe fn segment_page_start(segment: *const Segment, page: *const Page, block_size: usize, page_size: *mut usize) -> *mut u8 {
+pub unsafe fn _segment_page_start(segment: *const Segment, page: *const Page, block_size: usize, page_size: *mut usize) -> *mut u8 {
     let psize: usize = if (*segment).page_kind == PAGE_HUGE {
         (*segment).segment_size
     } else {
         1 << (*segment).page_shift
     };
-    let mut p: *mut u8 = (segment as usize + (*page).segment_idx as usize * psize) as _;
+    let mut p: *mut u8 = (segment_start(segment) as usize + (*page).segment_idx as usize * psize) as _;
 
     if ((*page).segment_idx == 0) {
         // the first page starts after the segment info (and possible guard page)
@@ -155,7 +155,10 @@ unsafe fn segment_size(capacity: usize, required: usize, pre_size: *mut size, in
     capacity = SMALL_PAGES_PER_SEGMENT;
   }
   */
-  let minsize: usize   = sizeof(segment_t) + ((capacity - 1) * sizeof(page_t)) + 16 /* padding */;
+  #[cfg(not(feature = "oob-meta"))]
+  let minsize: usize   = sizeof(segment_t) + (capacity * sizeof(page_t)) + 16 /* padding */;  // the page array follows the header
+  #[cfg(feature = "oob-meta")]
+  let minsize: usize   = 0;  // the segment info is kept out of band
   let guardsize: usize = 0;
   let isize: usize     = 0;
 
@@ -178,6 +181,269 @@ unsafe fn segment_size(capacity: usize, required: usize, pre_size: *mut size, in
 }
 
 
+/* -----------------------------------------------------------
+  Segment map
+  A bitmap with a bit for every `MI_SEGMENT_SIZE` aligned address that
+  starts a segment allocated from the OS, for all threads. It answers
+  whether a pointer is in memory of this allocator without touching
+  that memory. Only the first `MI_SEGMENT_SIZE` bytes of a (huge)
+  segment are covered, which includes the start of every block.
+  On 64-bit it covers the 48-bit user address space (8MiB of zeroed
+  static memory, of which the OS only maps the parts in use).
+----------------------------------------------------------- */
+
+#[cfg(target_pointer_width = "64")]
+const MI_MAX_ADDRESS: usize = 1 << 48;  // 256TB
+#[cfg(target_pointer_width = "32")]
+const MI_MAX_ADDRESS: usize = usize::MAX;
+
+const SEGMENT_MAP_BITS: usize = MI_MAX_ADDRESS / MI_SEGMENT_SIZE + 1;
+const SEGMENT_MAP_WSIZE: usize = (SEGMENT_MAP_BITS + 8 * MI_INTPTR_SIZE - 1) / (8 * MI_INTPTR_SIZE);
+
+static segment_map: [AtomicUsize; SEGMENT_MAP_WSIZE] = {
+  const EMPTY: AtomicUsize = AtomicUsize::new(0);
+  [EMPTY; SEGMENT_MAP_WSIZE]
+};
+
+// The word and bit of the segment at `start`, or `None` if it is beyond `MI_MAX_ADDRESS`
+#[inline]
+fn segment_map_index(start: usize) -> Option<(usize, usize)> {
+  let idx: usize = start / MI_SEGMENT_SIZE;
+  if idx >= SEGMENT_MAP_BITS { return None; }
+  Some((idx / (8 * MI_INTPTR_SIZE), 1 << (idx % (8 * MI_INTPTR_SIZE))))
+}
+
+fn segment_map_set(start: *mut u8, in_use: bool) {
+  if let Some((i, bit)) = segment_map_index(start as usize) {
+    if in_use { segment_map[i].fetch_or(bit, Ordering::Release); }
+         else { segment_map[i].fetch_and(!bit, Ordering::Release); }
+  }
+}
+
+// Call `visit` with every segment in the map
+unsafe fn segment_map_visit<F: FnMut(*mut Segment)>(mut visit: F) {
+  for (i, word) in segment_map.iter().enumerate() {
+    let mut bits: usize = word.load(Ordering::Relaxed);
+    while bits != 0 {
+      let bit: usize = bits.trailing_zeros() as usize;
+      bits &= bits - 1;
+      let start: usize = (i * 8 * MI_INTPTR_SIZE + bit) * MI_SEGMENT_SIZE;
+      let segment: *mut Segment = ptr_segment(start as *const u8);
+      if !segment.is_null() { visit(segment); }
+    }
+  }
+}
+
+/// Returns `true` if `p` points into memory allocated by this allocator,
+/// such as any pointer returned by it and not yet freed.
+/// Only the first 4MiB of a segment are covered: an interior pointer
+/// further into a huge block returns `false`.
+/// It does not access the memory at `p`.
+pub fn is_in_heap_region(p: *const u8) -> bool {
+  match segment_map_index(p as usize & !MI_SEGMENT_MASK) {
+    Some((i, bit)) => segment_map[i].load(Ordering::Relaxed) & bit != 0,
+    None => false,
+  }
+}
+
+/* -----------------------------------------------------------
+  Out-of-band segment metadata
+  With the `oob-meta` feature the segment info (the `Segment` header
+  and its `Page` array) is allocated apart from the segment memory, so
+  an overflow of a block can not reach it. `ptr_segment` then finds the
+  header of a segment through a two-level table indexed by the segment
+  address: the first level is static and covers the whole address range,
+  the second level tables are allocated on first use and never freed.
+----------------------------------------------------------- */
+
+#[cfg(feature = "oob-meta")]
+const SEGMENT_META_SIZE: usize = core::mem::size_of::<Segment>() + MI_SMALL_PAGES_PER_SEGMENT * core::mem::size_of::<Page>();
+
+#[cfg(feature = "oob-meta")]
+const SEGMENT_META_BITS: usize = (if MI_INTPTR_SIZE == 8 { 48 } else { 32 }) - MI_SEGMENT_SHIFT;
+#[cfg(feature = "oob-meta")]
+const SEGMENT_META_L2_BITS: usize = SEGMENT_META_BITS / 2;
+#[cfg(feature = "oob-meta")]
+const SEGMENT_META_L2_COUNT: usize = 1 << SEGMENT_META_L2_BITS;
+#[cfg(feature = "oob-meta")]
+const SEGMENT_META_L1_COUNT: usize = 1 << (SEGMENT_META_BITS - SEGMENT_META_L2_BITS);
+
+#[cfg(feature = "oob-meta")]
+static segment_meta_map: [AtomicPtr<AtomicPtr<Segment>>; SEGMENT_META_L1_COUNT] = {
+  const EMPTY: AtomicPtr<AtomicPtr<Segment>> = AtomicPtr::new(null_mut());
+  [EMPTY; SEGMENT_META_L1_COUNT]
+};
+
+// The entry for the segment at `start`, allocating its second level table if `create` is set
+#[cfg(feature = "oob-meta")]
+unsafe fn segment_meta_entry(start: usize, create: bool, stats: *mut Stats) -> *const AtomicPtr<Segment> {
+  let idx: usize = start >> MI_SEGMENT_SHIFT;
+  let l1: usize = idx >> SEGMENT_META_L2_BITS;
+  if l1 >= SEGMENT_META_L1_COUNT { return core::ptr::null(); }
+  let mut l2: *mut AtomicPtr<Segment> = segment_meta_map[l1].load(Ordering::Acquire);
+  if l2.is_null() {
+    if !create { return core::ptr::null(); }
+    // fresh OS memory is zeroed, that is, all entries are null
+    let fresh = _os_alloc(SEGMENT_META_L2_COUNT * core::mem::size_of::<AtomicPtr<Segment>>(), stats) as *mut AtomicPtr<Segment>;
+    if fresh.is_null() { return core::ptr::null(); }
+    match segment_meta_map[l1].compare_exchange(null_mut(), fresh, Ordering::AcqRel, Ordering::Acquire) {
+      Ok(_) => l2 = fresh,
+      Err(current) => {
+        _os_free(fresh as *mut u8, SEGMENT_META_L2_COUNT * core::mem::size_of::<AtomicPtr<Segment>>(), stats);
+        l2 = current;
+      }
+    }
+  }
+  l2.add(idx & (SEGMENT_META_L2_COUNT - 1))
+}
+
+// The header of the segment at `start` (which must be `MI_SEGMENT_SIZE` aligned), or null
+#[cfg(feature = "oob-meta")]
+pub unsafe fn _segment_meta_lookup(start: usize) -> *mut Segment {
+  let entry = segment_meta_entry(start, false, null_mut());
+  if entry.is_null() { null_mut() } else { (*entry).load(Ordering::Acquire) }
+}
+
+#[cfg(feature = "oob-meta")]
+unsafe fn segment_meta_set(start: *mut u8, segment: *mut Segment, stats: *mut Stats) -> bool {
+  let entry = segment_meta_entry(start as usize, !segment.is_null(), stats);
+  if entry.is_null() { return segment.is_null(); }
+  (*entry).store(segment, Ordering::Release);
+  true
+}
+
+// Allocate and register the header for the segment memory at `start`
+#[cfg(feature = "oob-meta")]
+unsafe fn segment_meta_alloc(start: *mut u8, stats: *mut Stats) -> *mut Segment {
+  let segment = _os_alloc(SEGMENT_META_SIZE, stats) as *mut Segment;
+  if segment.is_null() { return null_mut(); }
+  (*segment).start = start;
+  if !segment_meta_set(start, segment, stats) {
+    _os_free(segment as *mut u8, SEGMENT_META_SIZE, stats);
+    return null_mut();
+  }
+  segment
+}
+
+// Release the memory of a segment, and its header if that is kept out of band
+unsafe fn segment_os_release(segment: *mut Segment, segment_size: usize, stats: *mut Stats) {
+  abandoned_await_readers();
+  segment_map_set(segment_start(segment), false);
+  #[cfg(feature = "oob-meta")]
+  {
+    let start: *mut u8 = (*segment).start;
+    segment_meta_set(start, null_mut(), stats);
+    _os_free(segment as *mut u8, SEGMENT_META_SIZE, stats);
+    _os_free_ex(start, segment_size, false, stats);
+  }
+  #[cfg(not(feature = "oob-meta"))]
+  {
+    _os_free_ex(segment as *mut u8, segment_size, false, stats);
+  }
+}
+
+/* -----------------------------------------------------------
+  Guard pages
+  With `option_secure` a protected OS page follows the segment info.
+  At level 1 the last page of the segment ends in a guard page too;
+  at higher levels every page does. Guard pages can only be set in
+  committed memory, so committing a chunk protects its guards again.
+----------------------------------------------------------- */
+
+// Call `visit` with the offset of every guard page in `segment`
+unsafe fn segment_guard_offsets<F: FnMut(usize)>(segment: *const Segment, mut visit: F) {
+  if !option_is_enabled(option_secure) || (*segment).capacity == 0 { return; }
+  let os_page_size: usize = os_page_size();
+  visit((*segment).segment_info_size - os_page_size);
+  if option_get(option_secure) <= 1 || (*segment).capacity == 1 {
+    visit((*segment).segment_size - os_page_size);
+  }
+  else {
+    let page_size: usize = 1 << (*segment).page_shift;
+    for i in 0..(*segment).capacity {
+      visit((i+1)*page_size - os_page_size);
+    }
+  }
+}
+
+// Protect (or unprotect) the guard pages within `offset..offset+size` that are committed
+unsafe fn segment_protect_range(segment: *mut Segment, offset: usize, size: usize, protect: bool) {
+  segment_guard_offsets(segment, |guard| {
+    if guard < offset || guard >= offset + size { return; }
+    let committed = (*segment).segment_size > MI_SEGMENT_SIZE ||
+                    (*segment).commit_mask & (1 << (guard / MI_COMMIT_SIZE)) != 0;
+    if !committed { return; }
+    let p: *mut u8 = segment_start(segment).add(guard);
+    if protect { _os_protect(p, os_page_size()); } else { _os_unprotect(p, os_page_size()); }
+  });
+}
+
+unsafe fn segment_protect(segment: *mut Segment, protect: bool) {
+  segment_protect_range(segment, 0, (*segment).segment_size, protect);
+}
+
+/* -----------------------------------------------------------
+  Commit tracking
+  Segments are only reserved from the OS; their memory is committed
+  in `MI_COMMIT_SIZE` chunks when a page is first used, and each
+  segment tracks its committed chunks in `commit_mask`.
+  Huge segments are committed as a whole when allocated.
+----------------------------------------------------------- */
+
+// The commit mask of a fully committed segment of `size` bytes
+fn commit_mask_full(size: usize) -> u64 {
+  let chunks = (size + MI_COMMIT_SIZE - 1) / MI_COMMIT_SIZE;
+  if chunks >= 64 { !0 } else { (1u64 << chunks) - 1 }
+}
+
+// Commit the chunks overlapping `[offset, offset+size)` of the segment that are not committed yet
+unsafe fn segment_commit(segment: *mut Segment, offset: usize, size: usize, stats: *mut Stats) -> bool {
+  debug_assert!(offset + size <= (*segment).segment_size);
+  if (*segment).segment_size > MI_SEGMENT_SIZE { return true; } // always fully committed
+  let first = offset / MI_COMMIT_SIZE;
+  let last = (offset + size + MI_COMMIT_SIZE - 1) / MI_COMMIT_SIZE;
+  for i in first..last {
+    let bit: u64 = 1 << i;
+    if (*segment).commit_mask & bit != 0 { continue; }
+    let csize = core::cmp::min(MI_COMMIT_SIZE, (*segment).segment_size - i*MI_COMMIT_SIZE);
+    if !_os_commit(segment_start(segment).add(i*MI_COMMIT_SIZE), csize, stats) { return false; }
+    (*segment).commit_mask |= bit;
+    // committing made the guard pages in the chunk accessible again
+    segment_protect_range(segment, i*MI_COMMIT_SIZE, csize, true);
+  }
+  return true;
+}
+
+// Decommit the committed chunks that lie entirely within `[offset, offset+size)` of the segment
+unsafe fn segment_decommit(segment: *mut Segment, offset: usize, size: usize, stats: *mut Stats) {
+  debug_assert!(offset + size <= (*segment).segment_size);
+  if (*segment).segment_size > MI_SEGMENT_SIZE {
+    _os_decommit(segment_start(segment).add(offset), size, stats);
+    return;
+  }
+  let first = (offset + MI_COMMIT_SIZE - 1) / MI_COMMIT_SIZE;
+  let last = (offset + size) / MI_COMMIT_SIZE;
+  for i in first..last {
+    let bit: u64 = 1 << i;
+    if (*segment).commit_mask & bit == 0 { continue; }
+    _os_decommit(segment_start(segment).add(i*MI_COMMIT_SIZE), MI_COMMIT_SIZE, stats);
+    (*segment).commit_mask &= !bit;
+  }
+}
+
+// Number of committed bytes in the segment
+unsafe fn segment_committed_size(segment: *const Segment) -> usize {
+  if (*segment).segment_size > MI_SEGMENT_SIZE { return (*segment).segment_size; }
+  core::cmp::min((*segment).commit_mask.count_ones() as usize * MI_COMMIT_SIZE, (*segment).segment_size)
+}
+
+// Commit the memory of a page before it is used for the first time
+unsafe fn segment_page_commit(segment: *mut Segment, page: *const Page, stats: *mut Stats) -> bool {
+  let psize: usize = if (*segment).page_kind == PAGE_HUGE { (*segment).segment_size } else { 1 << (*segment).page_shift };
+  return segment_commit(segment, (*page).segment_idx as usize * psize, psize, stats);
+}
+
+
 /* -----------------------------------------------------------
 Segment caches
 We keep a small segment cache per thread to avoid repeated allocation
@@ -186,17 +452,27 @@ all again repeatedly. (We tried a one-element cache but that
 proves to be too small for certain workloads).
 ----------------------------------------------------------- */
 
-unsafe fn segments_track_size(segment_size: u32, tld: *mut SegmentsTld) {
-  if segment_size>=0 {stat_increase(*((*tld).stats).segments,1);}
-                  else { stat_decrease(*((*tld).stats).segments,1); }
-  (*tld).current_size += segment_size;
+unsafe fn segments_track_size(segment_size: isize, tld: *mut SegmentsTld) {
+  if segment_size>=0 { _stat_increase(&mut (*(*tld).stats).segments, 1); }
+                  else { _stat_decrease(&mut (*(*tld).stats).segments, 1); }
+  (*tld).current_size = ((*tld).current_size as isize + segment_size) as usize;
+  if (*tld).current_size > (*tld).peak_size {(*tld).peak_size = (*tld).current_size};
+  segments_track_global(segment_size);
+}
+
+// A segment changed size in place (shrunk, remapped or expanded).
+unsafe fn segments_track_resize(old_size: usize, new_size: usize, tld: *mut SegmentsTld) {
+  (*tld).current_size = (*tld).current_size - old_size + new_size;
   if (*tld).current_size > (*tld).peak_size {(*tld).peak_size = (*tld).current_size};
+  segments_track_global(new_size as isize - old_size as isize);
 }
 
 
 unsafe fn segment_os_free(segment: *mut Segment, segment_size: usize, tld: *mut SegmentsTld) {
-  segments_track_size(-(segment_size as long), tld);
-  _os_free(segment, segment_size, (*tld).stats);
+  segments_track_size(-(segment_size as isize), tld);
+  // only the committed part of the segment counts as committed memory
+  _stat_decrease(&mut (*(*tld).stats).committed, segment_committed_size(segment) as _);
+  segment_os_release(segment, segment_size, (*tld).stats);
 }
 
 // The segment cache is limited to be at most 1/8 of the peak size
@@ -204,12 +480,17 @@ unsafe fn segment_os_free(segment: *mut Segment, segment_size: usize, tld: *mut
 const SEGMENT_CACHE_MAX: usize = 32;
 const SEGMENT_CACHE_FRACTION: usize = 8;
 
+// A segment is not evicted before it has been in the cache for this long (in ms);
+// a thread that just freed it is likely to need it again soon.
+const SEGMENT_CACHE_MIN_AGE: i64 = 100;
+
 
 // Get a segment of at least `required` size.
-// If `required == SEGMENT_SIZE` the `segment_size` will match exactly
-unsafe fn _segment_cache_findx(tld: *mut SegmentsTld, required: usize, reverse: bool) -> *mut Segment {
+// If `required == SEGMENT_SIZE` the `segment_size` will match exactly.
+// Within a size the most recently cached segment comes first.
+unsafe fn segment_cache_find(tld: *mut SegmentsTld, required: usize) -> *mut Segment {
   debug_assert!(required % os_page_size() == 0);
-  segment: *mut Segment = (if reverse ? { (*tld).cache.last } else { (*tld).cache.first });
+  segment: *mut Segment = (*tld).cache.first;
   while (segment.is_not_null()) {
     if ((*segment).segment_size >= required) {
       (*tld).cache_count--;
@@ -225,12 +506,17 @@ unsafe fn _segment_cache_findx(tld: *mut SegmentsTld, required: usize, reverse:
       }
       // try to shrink the memory to match exactly
       else {
+        _stat_counter_increase(&mut (*(*tld).stats).segment_cache_shrinks, ((*segment).segment_size - required) as _);
         if (option_is_enabled(option_secure)) {
-          _os_unprotect(segment, (*segment).segment_size);
+          segment_protect(segment, false);
         }
-        if (_os_shrink(segment, (*segment).segment_size, required, (*tld).stats)) {
-          (*tld).current_size -= (*segment).segment_size;
-          (*tld).current_size += required;
+        // decommit the tail first so only the address range is left to release
+        segment_decommit(segment, required, (*segment).segment_size - required, (*tld).stats);
+        if (_os_shrink(segment_start(segment), (*segment).segment_size, required, false, (*tld).stats)) {
+          segments_track_resize((*segment).segment_size, required, tld);
+          if (*segment).segment_size > MI_SEGMENT_SIZE && required <= MI_SEGMENT_SIZE {
+            (*segment).commit_mask = commit_mask_full(required);
+          }
           (*segment).segment_size = required;
           return segment;
         }
@@ -241,18 +527,27 @@ unsafe fn _segment_cache_findx(tld: *mut SegmentsTld, required: usize, reverse:
         }
       }
     }
-    segment = (if reverse { (*segment).prev } else { (*segment).next });
+    segment = (*segment).next;
   }
   return null_mut();
 }
 
-unsafe fn segment_cache_find(tld: *mut SegmentsTld, required: usize) -> *mut Segment {
-  return _segment_cache_findx(tld,required,false);
-}
-
+// Remove the least recently cached segment of the largest size that is at
+// least `SEGMENT_CACHE_MIN_AGE` old. The cache is sorted by size and new segments
+// are inserted in front of their size, so that is the first old enough segment
+// from the end. Returns null if all segments were cached too recently.
 unsafe fn segment_cache_evict(tld: *mut SegmentsTld) -> *mut Segment {
-  // TODO: random eviction instead?
-  return _segment_cache_findx(tld, 0, true /* from the end */);
+  let now: i64 = _clock_now();
+  let mut segment: *mut Segment = (*tld).cache.last;
+  while !segment.is_null() && now - (*segment).cache_time < SEGMENT_CACHE_MIN_AGE {
+    segment = (*segment).prev;
+  }
+  if segment.is_null() { return null_mut(); }
+  (*tld).cache_count -= 1;
+  (*tld).cache_size -= (*segment).segment_size;
+  segment_queue_remove(&(*tld).cache, segment);
+  _stat_counter_increase(&mut (*(*tld).stats).segment_cache_evictions, (*segment).segment_size as _);
+  segment
 }
 
 unsafe fn segment_cache_full(tld: *mut SegmentsTld) -> bool {
@@ -261,8 +556,8 @@ unsafe fn segment_cache_full(tld: *mut SegmentsTld) -> bool {
   // take the opportunity to reduce the segment cache if it is too large (now)
   while ((*tld).cache_size*SEGMENT_CACHE_FRACTION >= (*tld).peak_size + 1) {
     segment: *mut Segment = segment_cache_evict(tld);
-    debug_assert!(segment.is_not_null());
-    if segment.is_not_null() {segment_os_free(segment, (*segment).segment_size, tld)};
+    if segment.is_null() { break; }  // everything was cached just now
+    if !segment_cache_global_push(segment, tld) {segment_os_free(segment, (*segment).segment_size, tld)};
   }
   return true;
 }
@@ -273,9 +568,16 @@ unsafe fn segment_cache_insert(segment: *mut Segment, tld: *mut SegmentsTld) ->
   assert!(!segment_queue_contains(&(*tld).cache, segment));
   if segment_cache_full(tld) {return false};
   if (option_is_enabled(option_cache_reset) && !option_is_enabled(option_page_reset)) {
-    _os_reset(segment as *mut u8 + (*segment).segment_info_size, (*segment).segment_size - (*segment).segment_info_size, (*tld).stats);
+    let delay: i64 = option_get(option_reset_delay) as i64;
+    if delay > 0 {
+      (*segment).purge_expire = _clock_now() + delay;
+    }
+    else {
+      segment_cache_purge(segment, (*tld).stats);
+    }
   }
-  // insert ordered
+  (*segment).cache_time = _clock_now();
+  // insert ordered, in front of the segments of the same size
   seg: *mut Segment = (*tld).cache.first;
   while (seg.is_not_null() && (*seg).segment_size < (*segment).segment_size) {
     seg = (*seg).next;
@@ -286,11 +588,132 @@ unsafe fn segment_cache_insert(segment: *mut Segment, tld: *mut SegmentsTld) ->
   return true;
 }
 
+/* -----------------------------------------------------------
+  Global segment cache
+  Segments that do not fit in the cache of a thread are spilled to a
+  small process-wide cache where other threads can pick them up before
+  going to the OS. A slot is filled and emptied with a single atomic
+  operation so no lock is needed. Only segments of exactly
+  `MI_SEGMENT_SIZE` are shared: they fit any non-huge request and we
+  never have to look inside a segment before owning it.
+----------------------------------------------------------- */
+
+const SEGMENT_CACHE_GLOBAL_MAX: usize = 64;
+
+static segment_cache_global: [AtomicPtr<Segment>; SEGMENT_CACHE_GLOBAL_MAX] = {
+  const EMPTY: AtomicPtr<Segment> = AtomicPtr::new(null_mut());
+  [EMPTY; SEGMENT_CACHE_GLOBAL_MAX]
+};
+static segment_cache_global_count: AtomicUsize = AtomicUsize::new(0);
+
+// Size of all segments in the process, including the cached ones.
+static segments_current_size: AtomicUsize = AtomicUsize::new(0);
+static segments_peak_size: AtomicUsize = AtomicUsize::new(0);
+
+fn segments_track_global(segment_size: isize) {
+  if segment_size >= 0 {
+    let current = segments_current_size.fetch_add(segment_size as usize, Ordering::Relaxed) + segment_size as usize;
+    segments_peak_size.fetch_max(current, Ordering::Relaxed);
+  }
+  else {
+    segments_current_size.fetch_sub(segment_size.unsigned_abs(), Ordering::Relaxed);
+  }
+}
+
+// The global cache is limited to 1/8 of the peak size of all segments in the process.
+fn segment_cache_global_full() -> bool {
+  let count = segment_cache_global_count.load(Ordering::Relaxed);
+  count >= SEGMENT_CACHE_GLOBAL_MAX ||
+    (count + 1)*MI_SEGMENT_SIZE*SEGMENT_CACHE_FRACTION > segments_peak_size.load(Ordering::Relaxed)
+}
+
+// Give a free segment to the global cache; returns `false` if it is not
+// shareable or the cache is full, in which case the caller still owns it.
+unsafe fn segment_cache_global_push(segment: *mut Segment, tld: *mut SegmentsTld) -> bool {
+  if (*segment).segment_size != MI_SEGMENT_SIZE || segment_cache_global_full() { return false; }
+  // segments that did not pass through the cache of a thread have no purge scheduled yet
+  if (*segment).purge_expire == 0 && option_is_enabled(option_cache_reset) && !option_is_enabled(option_page_reset) {
+    (*segment).purge_expire = _clock_now() + option_get(option_reset_delay) as i64;
+  }
+  // read before publishing it: once in a slot another thread may take it
+  let committed: usize = segment_committed_size(segment);
+  for slot in segment_cache_global.iter() {
+    if slot.load(Ordering::Relaxed).is_null() &&
+       slot.compare_exchange(null_mut(), segment, Ordering::Release, Ordering::Relaxed).is_ok() {
+      segment_cache_global_count.fetch_add(1, Ordering::Relaxed);
+      // it no longer belongs to this thread
+      (*tld).current_size -= MI_SEGMENT_SIZE;
+      segment_stats_move(committed, (*tld).stats, addr_of_mut!(stats_main));
+      return true;
+    }
+  }
+  false
+}
+
+// Move the `segments` and `committed` stats of a segment between a thread and the global cache
+unsafe fn segment_stats_move(committed: usize, from: *mut Stats, to: *mut Stats) {
+  if from == to { return; }
+  _stat_decrease(&mut (*from).segments, 1);
+  _stat_increase(&mut (*to).segments, 1);
+  _stat_decrease(&mut (*from).committed, committed as _);
+  _stat_increase(&mut (*to).committed, committed as _);
+}
+
+// Take a segment of `MI_SEGMENT_SIZE` from the global cache, or null if it is empty.
+unsafe fn segment_cache_global_pop(tld: *mut SegmentsTld) -> *mut Segment {
+  if segment_cache_global_count.load(Ordering::Relaxed) == 0 { return null_mut(); }
+  for slot in segment_cache_global.iter() {
+    if slot.load(Ordering::Relaxed).is_null() { continue; }
+    let segment: *mut Segment = slot.swap(null_mut(), Ordering::Acquire);
+    if !segment.is_null() {
+      segment_cache_global_count.fetch_sub(1, Ordering::Relaxed);
+      segment_stats_move(segment_committed_size(segment), addr_of_mut!(stats_main), (*tld).stats);
+      (*tld).current_size += MI_SEGMENT_SIZE;
+      if (*tld).current_size > (*tld).peak_size {(*tld).peak_size = (*tld).current_size};
+      return segment;
+    }
+  }
+  null_mut()
+}
+
+// Purge the globally cached segments whose reset delay expired.
+// A segment is taken out of its slot while purging it.
+pub unsafe fn _segment_cache_global_purge(stats: *mut Stats) {
+  if segment_cache_global_count.load(Ordering::Relaxed) == 0 { return; }
+  let now: i64 = _clock_now();
+  for slot in segment_cache_global.iter() {
+    if slot.load(Ordering::Relaxed).is_null() { continue; }
+    let segment: *mut Segment = slot.swap(null_mut(), Ordering::Acquire);
+    if segment.is_null() { continue; }
+    if (*segment).purge_expire != 0 && (*segment).purge_expire <= now {
+      segment_cache_purge(segment, stats);
+    }
+    // put it back; the slot may have been filled meanwhile so look for any empty one
+    if !segment_cache_global.iter().any(|s| s.compare_exchange(null_mut(), segment, Ordering::Release, Ordering::Relaxed).is_ok()) {
+      segment_cache_global_count.fetch_sub(1, Ordering::Relaxed);
+      segments_track_global(-(MI_SEGMENT_SIZE as isize));
+      _stat_decrease(&mut (*stats).segments, 1);
+      _stat_decrease(&mut (*stats).committed, segment_committed_size(segment) as _);
+      segment_os_release(segment, MI_SEGMENT_SIZE, stats);
+    }
+  }
+}
+
 // called by ending threads to free cached segments
 pub unsafe fn _segment_thread_collect(tld: *mut SegmentsTld) {
+  lock_acquire(&(*tld).lock);
+  segment_thread_collect(tld);
+  lock_release(&(*tld).lock);
+}
+
+unsafe fn segment_thread_collect(tld: *mut SegmentsTld) {
+  segment_delayed_purge(tld, true);
   segment: *mut Segment;
   while ((segment = segment_cache_find(tld,0)).is_not_null()) {
-    segment_os_free(segment, (*segment).segment_size, tld);
+    // hand them over to other threads if there is room
+    if !segment_cache_global_push(segment, tld) {
+      segment_os_free(segment, (*segment).segment_size, tld);
+    }
   }
   debug_assert!((*tld).cache_count == 0 && (*tld).cache_size == 0);
   debug_assert!(segment_queue_is_empty(&(*tld).cache));
@@ -321,45 +744,82 @@ unsafe fn segment_alloc(required: usize, page_kind: PageKind, page_shift: usize,
   let pre_size: usize;
   let segment_size: usize = segment_size( capacity, required, &pre_size, &info_size);
   debug_assert!(segment_size >= required);
-  let page_size: usize = (if page_kind == PAGE_HUGE ? { segment_size } else { 1 << page_shift });
 
   // Allocate the segment
   let mut segment: *mut Segment = null_mut();
 
   // try to get it from our caches
   segment = segment_cache_find(tld,segment_size);
+  if segment.is_null() && segment_size == MI_SEGMENT_SIZE {
+    segment = segment_cache_global_pop(tld);
+  }
+  if segment.is_null() {
+    _stat_counter_increase(&mut (*(*tld).stats).segment_cache_misses, segment_size as _);
+  }
+  else {
+    _stat_counter_increase(&mut (*(*tld).stats).segment_cache_hits, segment_size as _);
+  }
   debug_assert!(segment.is_null() ||
                      (segment_size==SEGMENT_SIZE && segment_size == (*segment).segment_size) ||
                       (segment_size!=SEGMENT_SIZE && segment_size <= (*segment).segment_size));
   if (segment.is_not_null() && option_is_enabled(option_secure) && ((*segment).page_kind != page_kind || (*segment).segment_size != segment_size)) {
-    _os_unprotect(segment,(*segment).segment_size);
+    // the guard pages of the old layout become part of the new pages
+    segment_protect(segment, false);
   }
 
-  // and otherwise allocate it from the OS
+  // the header of a cached segment is committed and still holds its commit mask
+  let mut commit_mask: u64 = if segment.is_null() { 0 } else { (*segment).commit_mask };
+
+  // and otherwise allocate it from the OS; only huge segments are committed right away
   if (segment.is_null()) {
-    segment = _os_alloc_aligned(segment_size, SEGMENT_SIZE, true, os_tld) as *mut Segment;
-    if segment.is_null() {return null_mut()};
-    segments_track_size(segment_size as u32, tld);
+    let commit: bool = page_kind == PAGE_HUGE;
+    let start: *mut u8 = _os_alloc_aligned(segment_size, SEGMENT_SIZE, commit, os_tld);
+    if start.is_null() {return null_mut()};
+    segments_track_size(segment_size as isize, tld);
+    if commit { commit_mask = commit_mask_full(segment_size); }
+    #[cfg(not(feature = "oob-meta"))]
+    {
+      segment = start as *mut Segment;
+    }
+    #[cfg(feature = "oob-meta")]
+    {
+      segment = segment_meta_alloc(start, (*tld).stats);
+      if segment.is_null() {
+        segments_track_size(-(segment_size as isize), tld);
+        _os_free_ex(start, segment_size, false, (*tld).stats);
+        return null_mut();
+      }
+    }
+    segment_map_set(start, true);
   }
 
-  debug_assert!(segment as usize % SEGMENT_SIZE == 0);
+  debug_assert!(segment_start(segment) as usize % SEGMENT_SIZE == 0);
 
-  memset(segment, 0, info_size);
-  if (option_is_enabled(option_secure)) {
-    // in secure mode, we set up a protected page in between the segment info
-    // and the page data
-    debug_assert!( info_size == pre_size - os_page_size() && info_size % os_page_size() == 0);
-    _os_protect( segment as *mut u8 + info_size, (pre_size - info_size) );
-    let os_page_size: usize = os_page_size();
-    if (option_get(option_secure) <= 1) {
-      // and protect the last page too
-      _os_protect( segment as *mut u8 + segment_size - os_page_size, os_page_size );
-    } else {
-      // protect every page
-      for i in 0..capacity {
-        _os_protect( segment as *mut u8 + (i+1)*page_size - os_page_size, os_page_size );
-      }
+  // commit the segment info before writing to it
+  debug_assert!(pre_size <= MI_COMMIT_SIZE);
+  if commit_mask & 1 == 0 {
+    if !_os_commit(segment_start(segment), MI_COMMIT_SIZE, (*tld).stats) {
+      segments_track_size(-(segment_size as isize), tld);
+      segment_os_release(segment, segment_size, (*tld).stats);
+      return null_mut();
     }
+    commit_mask |= 1;
+  }
+
+  #[cfg(not(feature = "oob-meta"))]
+  memset(segment, 0, info_size);
+  #[cfg(feature = "oob-meta")]
+  {
+    let start: *mut u8 = (*segment).start;
+    core::ptr::write_bytes(segment as *mut u8, 0, SEGMENT_META_SIZE);
+    (*segment).start = start;
+  }
+  (*segment).pages = segment.add(1) as *mut Page;  // the page array follows the header
+  (*segment).commit_mask = commit_mask;
+  (*segment).segment_size = segment_size;
+  if page_kind == PAGE_HUGE && !segment_commit(segment, 0, segment_size, (*tld).stats) {
+    segment_os_free(segment, segment_size, tld);
+    return null_mut();
   }
 
   (*segment).page_kind  = page_kind;
@@ -367,6 +827,12 @@ unsafe fn segment_alloc(required: usize, page_kind: PageKind, page_shift: usize,
   (*segment).page_shift = page_shift;
   (*segment).segment_size = segment_size;
   (*segment).segment_info_size = pre_size;
+  if (option_is_enabled(option_secure)) {
+    // in secure mode, we set up a protected page in between the segment info
+    // and the page data, and at the end of the last (or every) page
+    debug_assert!( info_size == pre_size - os_page_size() && info_size % os_page_size() == 0);
+    segment_protect(segment, true);
+  }
   (*segment).thread_id  = _thread_id();
   (*segment).cookie = _ptr_cookie(segment);
   for i in 0..(*segment).capacity {
@@ -407,6 +873,7 @@ unsafe fn segment_free(segment: *mut Segment, force: bool, tld: *mut SegmentsTld
   // update reset memory statistics
   for i in 0..(*segment).capacity {
     page: *mut page = &(*segment).pages[i];
+    segment_page_reset_remove(page, tld);
     if ((*page).is_reset) {
       (*page).is_reset = false;
       stat_decrease( (*(*tld).stats).reset,page_size(page));
@@ -416,6 +883,9 @@ unsafe fn segment_free(segment: *mut Segment, force: bool, tld: *mut SegmentsTld
   if (!force && segment_cache_insert(segment, tld)) {
     // it is put in our cache
   }
+  else if (!force && segment_cache_global_push(segment, tld)) {
+    // or shared with other threads
+  }
   else {
     // otherwise return it to the OS
     segment_os_free(segment, (*segment).segment_size, tld);
@@ -454,7 +924,8 @@ unsafe fn segment_find_free(segment: *mut Segment) -> *mut page {
 
 // unsafe fn segment_abandon(segment: *mut Segment, tld: *mut SegmentsTld);
 
-unsafe fn segment_page_clear(segment: *mut Segment, page: *mut page, _stats: *mut stats) {
+unsafe fn segment_page_clear(segment: *mut Segment, page: *mut page, tld: *mut SegmentsTld) {
+    let stats: *mut Stats = (*tld).stats;
     debug_assert!((*page).segment_in_use);
     debug_assert!(page_all_free(page));
     let inuse: usize = (*page).capacity * (*page).block_size;
@@ -462,7 +933,8 @@ unsafe fn segment_page_clear(segment: *mut Segment, page: *mut page, _stats: *mu
     stat_decrease( (*stats).pages, 1);
 
     // reset the page memory to reduce memory pressure?
-    if (!(*page).is_reset && option_is_enabled(option_page_reset)) {
+    let delayed: bool = option_get(option_reset_delay) > 0;
+    if (!(*page).is_reset && option_is_enabled(option_page_reset) && !delayed) {
         let psize: usize;
         start: *mut u8 = _page_start(segment, page, &psize);
         stat_increase( (*stats).reset, psize);  // for stats we assume resetting the full page
@@ -480,15 +952,27 @@ unsafe fn segment_page_clear(segment: *mut Segment, page: *mut page, _stats: *mu
     (*page).segment_in_use = false;
     (*page).is_reset = is_reset;
     (*segment).used -= 1;
+
+    // or reset it once it stayed unused for a while, as it is often reused right away
+    if (!is_reset && option_is_enabled(option_page_reset) && delayed) {
+        segment_page_reset_delayed(page, tld);
+    }
 }
 
-unsafe fn _segment_page_free(page: *mut page, force: bool, tld: *mut SegmentsTld) {
+pub unsafe fn _segment_page_free(page: *mut Page, force: bool, tld: *mut SegmentsTld) {
   assert(page.is_not_null());
   segment: *mut Segment = _page_segment(page);
   assert!(segment_is_valid(segment));
+  lock_acquire(&(*tld).lock);
+
+  // a guarded block and its guard page must be accessible again before the memory is reused
+  if (*segment).is_guarded {
+    _os_unprotect(page_start(segment, page, null_mut()), (*page).block_size);
+    (*segment).is_guarded = false;
+  }
 
   // mark it as free now
-  segment_page_clear(segment, page, (*tld).stats);
+  segment_page_clear(segment, page, tld);
 
   if ((*segment).used == 0) {
     // no more used pages; remove from the free list and free the segment
@@ -505,6 +989,127 @@ unsafe fn _segment_page_free(page: *mut page, force: bool, tld: *mut SegmentsTld
       segment_enqueue(&(*tld).small_free, segment);
     }
   }
+  lock_release(&(*tld).lock);
+}
+
+
+/* -----------------------------------------------------------
+  Delayed reset
+  With `option_reset_delay` set, freed pages (with `option_page_reset`)
+  and cached segments (with `option_cache_reset`) are only reset once
+  they stayed unused for that many milliseconds.
+----------------------------------------------------------- */
+
+unsafe fn page_reset_is_scheduled(page: *const Page) -> bool {
+  (*page).reset_expire != 0
+}
+
+// Queue a freed page to be reset when the reset delay expires
+unsafe fn segment_page_reset_delayed(page: *mut Page, tld: *mut SegmentsTld) {
+  debug_assert!(!(*page).segment_in_use && !page_reset_is_scheduled(page));
+  let pq: *mut PageQueue = &mut (*tld).pages_reset;
+  (*page).reset_expire = _clock_now() + option_get(option_reset_delay) as i64;
+  (*page).next = null_mut();
+  (*page).prev = (*pq).last;
+  if !(*pq).last.is_null() { (*(*pq).last).next = page; } else { (*pq).first = page; }
+  (*pq).last = page;
+}
+
+// Remove a page from the reset queue, if it is in there
+unsafe fn segment_page_reset_remove(page: *mut Page, tld: *mut SegmentsTld) {
+  if !page_reset_is_scheduled(page) { return; }
+  let pq: *mut PageQueue = &mut (*tld).pages_reset;
+  if !(*page).prev.is_null() { (*(*page).prev).next = (*page).next; } else { (*pq).first = (*page).next; }
+  if !(*page).next.is_null() { (*(*page).next).prev = (*page).prev; } else { (*pq).last = (*page).prev; }
+  (*page).next = null_mut();
+  (*page).prev = null_mut();
+  (*page).reset_expire = 0;
+}
+
+// Reset the memory of a free page
+unsafe fn segment_page_reset(segment: *mut Segment, page: *mut Page, stats: *mut Stats) {
+  debug_assert!(!(*page).segment_in_use);
+  if (*page).is_reset { return; }
+  let mut psize: usize = 0;
+  let start: *mut u8 = page_start(segment, page, &mut psize);
+  _stat_increase(&mut (*stats).reset, psize as _);  // for stats we assume resetting the full page
+  (*page).is_reset = true;
+  _os_reset(start, psize, stats);
+}
+
+// Reset the memory of a cached segment (except for the segment info). It stays
+// committed and accessible, whether the reset was delayed or not.
+unsafe fn segment_cache_purge(segment: *mut Segment, stats: *mut Stats) {
+  (*segment).purge_expire = 0;
+  let start: *mut u8 = segment_start(segment).add((*segment).segment_info_size);
+  let size: usize = (*segment).segment_size - (*segment).segment_info_size;
+  _os_reset(start, size, stats);
+}
+
+// Reset the queued pages and purge the cached segments whose delay expired (or all of them if `force`).
+unsafe fn segment_delayed_purge(tld: *mut SegmentsTld, force: bool) {
+  if (*tld).pages_reset.first.is_null() && (*tld).cache.first.is_null() { return; }
+  let now: i64 = _clock_now();
+  // the queue is in order of expiration
+  loop {
+    let page: *mut Page = (*tld).pages_reset.first;
+    if page.is_null() || (!force && (*page).reset_expire > now) { break; }
+    segment_page_reset_remove(page, tld);
+    segment_page_reset(page_segment(page), page, (*tld).stats);
+  }
+  let mut segment: *mut Segment = (*tld).cache.first;
+  while !segment.is_null() {
+    if (*segment).purge_expire != 0 && (force || (*segment).purge_expire <= now) {
+      segment_cache_purge(segment, (*tld).stats);
+    }
+    segment = (*segment).next;
+  }
+}
+
+// This runs on the page allocation slow path but can be called at any time,
+// e.g. on a heartbeat or from the purge thread for another thread's `tld`.
+pub unsafe fn _segment_delayed_purge(tld: *mut SegmentsTld, force: bool) {
+  lock_acquire(&(*tld).lock);
+  segment_delayed_purge(tld, force);
+  lock_release(&(*tld).lock);
+}
+
+// Purge the free pages of abandoned segments that stayed abandoned longer than the reset delay.
+// The whole list is taken while doing so; reclaiming threads just find it empty for a moment.
+pub unsafe fn _segment_abandoned_purge(stats: *mut Stats) {
+  let mut head: usize = abandoned.load(Ordering::Relaxed);
+  loop {
+    if abandoned_ptr(head).is_null() { return; }
+    match abandoned.compare_exchange_weak(head, abandoned_next_tag(head), Ordering::Acquire, Ordering::Relaxed) {
+      Ok(_) => break,
+      Err(current) => head = current,
+    }
+  }
+  let first: *mut Segment = abandoned_ptr(head);
+  let now: i64 = _clock_now();
+  let mut last: *mut Segment = first;
+  let mut segment: *mut Segment = first;
+  while !segment.is_null() {
+    if (*segment).purge_expire != 0 && (*segment).purge_expire <= now {
+      (*segment).purge_expire = 0;
+      for i in 0..(*segment).capacity {
+        let page: *mut Page = (*segment).pages.add(i);
+        if !(*page).segment_in_use { segment_page_reset(segment, page, stats); }
+      }
+    }
+    last = segment;
+    segment = (*segment).abandoned_next;
+  }
+  // and put them back in front of any segments abandoned in the meantime
+  let mut head: usize = abandoned.load(Ordering::Relaxed);
+  loop {
+    (*last).abandoned_next = abandoned_ptr(head);
+    let new: usize = first as usize | abandoned_next_tag(head);
+    match abandoned.compare_exchange_weak(head, new, Ordering::Release, Ordering::Relaxed) {
+      Ok(_) => break,
+      Err(current) => head = current,
+    }
+  }
 }
 
 
@@ -515,9 +1120,137 @@ unsafe fn _segment_page_free(page: *mut page, force: bool, tld: *mut SegmentsTld
 // When threads terminate, they can leave segments with
 // live blocks (reached through other threads). Such segments
 // are "abandoned" and will be reclaimed by other threads to
-// reuse their pages and/or free them eventually
-static abandoned: AtomicPtr<Segment> = AtomicPtr::new();
-static abandoned_count: AtomicUsize = 0;
+// reuse their pages and/or free them eventually.
+//
+// The abandoned segments form a lock-free stack. Segments are
+// `MI_SEGMENT_SIZE` aligned so the low bits of `abandoned` hold a tag
+// that changes on every update: a pop that raced with another pop and
+// push of the same segment (ABA) fails its compare-exchange.
+// Popping reads `abandoned_next` of a segment the thread does not own
+// yet, so segment memory is only released when no pop is in progress.
+static abandoned: AtomicUsize = AtomicUsize::new(0);
+static abandoned_count: AtomicUsize = AtomicUsize::new(0);    // estimated
+static abandoned_readers: AtomicUsize = AtomicUsize::new(0);  // threads in `abandoned_pop`
+
+// The list holds segment addresses; with `oob-meta` the header is looked up
+#[inline]
+unsafe fn abandoned_ptr(head: usize) -> *mut Segment {
+  let start: usize = head & !MI_SEGMENT_MASK;
+  #[cfg(feature = "oob-meta")]
+  {
+    if start == 0 { null_mut() } else { _segment_meta_lookup(start) }
+  }
+  #[cfg(not(feature = "oob-meta"))]
+  {
+    start as *mut Segment
+  }
+}
+
+#[inline]
+unsafe fn abandoned_addr(segment: *mut Segment) -> usize {
+  if segment.is_null() { 0 } else { segment_start(segment) as usize }
+}
+
+#[inline]
+fn abandoned_next_tag(head: usize) -> usize {
+  head.wrapping_add(1) & MI_SEGMENT_MASK
+}
+
+unsafe fn abandoned_push(segment: *mut Segment) {
+  debug_assert!(segment_start(segment) as usize & MI_SEGMENT_MASK == 0);
+  let mut head: usize = abandoned.load(Ordering::Relaxed);
+  loop {
+    (*segment).abandoned_next = abandoned_ptr(head);
+    let new: usize = abandoned_addr(segment) | abandoned_next_tag(head);
+    match abandoned.compare_exchange_weak(head, new, Ordering::Release, Ordering::Relaxed) {
+      Ok(_) => break,
+      Err(current) => head = current,
+    }
+  }
+  abandoned_count.fetch_add(1, Ordering::Relaxed);
+}
+
+unsafe fn abandoned_pop() -> *mut Segment {
+  if abandoned_ptr(abandoned.load(Ordering::Relaxed)).is_null() { return null_mut(); }
+  abandoned_readers.fetch_add(1, Ordering::SeqCst);
+  let mut head: usize = abandoned.load(Ordering::SeqCst);
+  let segment: *mut Segment = loop {
+    let segment: *mut Segment = abandoned_ptr(head);
+    if segment.is_null() { break segment; }
+    let new: usize = abandoned_addr((*segment).abandoned_next) | abandoned_next_tag(head);
+    match abandoned.compare_exchange_weak(head, new, Ordering::Acquire, Ordering::Relaxed) {
+      Ok(_) => break segment,
+      Err(current) => head = current,
+    }
+  };
+  abandoned_readers.fetch_sub(1, Ordering::Release);
+  if !segment.is_null() {
+    abandoned_count.fetch_sub(1, Ordering::Relaxed);
+    (*segment).abandoned_next = null_mut();
+  }
+  segment
+}
+
+// Visit the abandoned segments without taking them; none of them is released meanwhile.
+// A segment that is reclaimed during the visit ends it early.
+pub unsafe fn _segment_abandoned_visit<F: FnMut(*mut Segment)>(mut visit: F) {
+  abandoned_readers.fetch_add(1, Ordering::SeqCst);
+  let mut segment: *mut Segment = abandoned_ptr(abandoned.load(Ordering::SeqCst));
+  while !segment.is_null() {
+    visit(segment);
+    segment = (*segment).abandoned_next;
+  }
+  abandoned_readers.fetch_sub(1, Ordering::Release);
+}
+
+// Called in the child after `fork`: threads that were reading the
+// abandoned list in the parent do not exist there, and the count may be
+// off by the pushes and pops they were in the middle of. The list itself
+// is consistent as it only changes through single atomic operations.
+pub unsafe fn _segment_fork_child() {
+  abandoned_readers.store(0, Ordering::Relaxed);
+  let mut count: usize = 0;
+  let mut segment: *mut Segment = abandoned_ptr(abandoned.load(Ordering::Relaxed));
+  while !segment.is_null() {
+    count += 1;
+    segment = (*segment).abandoned_next;
+  }
+  abandoned_count.store(count, Ordering::Relaxed);
+}
+
+// Called in the child after `fork` for a thread that does not exist there,
+// with the lock of its `tld` still held from before the fork: release its
+// cached segments and abandon all segments in use so other threads can
+// reclaim them. Its page queues may have been halfway through an update, so
+// the pages are found through the segment map instead and detached from the
+// heap directly.
+pub unsafe fn _segment_fork_abandon(owner: usize, tld: *mut SegmentsTld) {
+  segment_thread_collect(tld);
+  segment_map_visit(|segment| {
+    if (*segment).thread_id != owner || (*segment).used == 0 { return; }
+    for i in 0..(*segment).capacity {
+      let page: *mut Page = (*segment).pages.add(i);
+      if !(*page).segment_in_use { continue; }
+      (*page).heap = null_mut();
+      (*page).next = null_mut();
+      (*page).prev = null_mut();
+      #[cfg(feature = "secure")]
+      {
+        (*page).quarantined = 0;  // the quarantine is gone with the heap; those blocks stay in use
+      }
+      _stat_increase(&mut (*(*tld).stats).pages_abandoned, 1);
+    }
+    (*segment).abandoned = (*segment).used;
+    segment_abandon(segment, tld);
+  });
+}
+
+// Wait until no thread can be reading the header of a segment we are about to release.
+fn abandoned_await_readers() {
+  while abandoned_readers.load(Ordering::SeqCst) != 0 {
+    core::hint::spin_loop();
+  }
+}
 
 unsafe fn segment_abandon(segment: *mut Segment, tld: *mut SegmentsTld) {
   debug_assert!((*segment).used == (*segment).abandoned);
@@ -525,93 +1258,101 @@ unsafe fn segment_abandon(segment: *mut Segment, tld: *mut SegmentsTld) {
   debug_assert!((*segment).abandoned_next.is_null());
   assert!(segment_is_valid(segment));
   // remove the segment from the free page queue if needed
-  if (segment_is_in_free_queue(segment,tld)) {
-    assert((*segment).page_kind == PAGE_SMALL); // for now we only support small pages
-    assert!(segment_queue_contains(&(*tld).small_free, segment));
-    segment_queue_remove(&(*tld).small_free, segment);
+  if segment_is_in_free_queue(segment, tld) {
+    debug_assert!((*segment).page_kind == PAGE_SMALL); // for now we only support small pages
+    assert!(segment_queue_contains(&mut (*tld).small_free, segment));
+    segment_queue_remove(&mut (*tld).small_free, segment);
   }
   debug_assert!((*segment).next.is_null() && (*segment).prev.is_null());
+  // the reset queue is per thread, so reset the pending pages now
+  for i in 0..(*segment).capacity {
+    let page: *mut Page = (*segment).pages.add(i);
+    if page_reset_is_scheduled(page) {
+      segment_page_reset_remove(page, tld);
+      segment_page_reset(segment, page, (*tld).stats);
+    }
+  }
+  // the segment no longer counts for this thread (but still for the process)
+  (*tld).current_size -= (*segment).segment_size;
   // all pages in the segment are abandoned; add it to the abandoned list
   (*segment).thread_id = 0;
-  do {
-    (*segment).abandoned_next = (abandoned: *mut Segment;
-  } while (!atomic_compare_exchange_ptr((volatile void**)&abandoned, segment, (*segment).abandoned_next));
-  atomic_increment(&abandoned_count);
-  stat_increase( (*(*tld).stats).segments_abandoned,1);
+  (*segment).purge_expire = _clock_now() + option_get(option_reset_delay) as i64;
+  abandoned_push(segment);
+  _stat_increase(&mut (*(*tld).stats).segments_abandoned, 1);
 }
 
-unsafe fn _segment_page_abandon(page: *mut page, tld: *mut SegmentsTld) {
-  assert(page.is_not_null());
-  segment: *mut Segment = _page_segment(page);
+pub unsafe fn _segment_page_abandon(page: *mut Page, tld: *mut SegmentsTld) {
+  debug_assert!(!page.is_null());
+  let segment: *mut Segment = page_segment(page);
   assert!(segment_is_valid(segment));
-  (*segment).abandoned++;
-  stat_increase( (*(*tld).stats).pages_abandoned, 1);
+  lock_acquire(&(*tld).lock);
+  (*segment).abandoned += 1;
+  _stat_increase(&mut (*(*tld).stats).pages_abandoned, 1);
   debug_assert!((*segment).abandoned <= (*segment).used);
-  if ((*segment).used == (*segment).abandoned) {
+  if (*segment).used == (*segment).abandoned {
     // all pages are abandoned, abandon the entire segment
-    segment_abandon(segment,tld);
+    segment_abandon(segment, tld);
   }
+  lock_release(&(*tld).lock);
 }
 
-unsafe fn _segment_try_reclaim_abandoned( heap: *mut heap, try_all: bool, tld: *mut SegmentsTld) -> bool {
-  let reclaimed: usize = 0;
-  let atmost: usize;
-  if (try_all) {
-    atmost = abandoned_count+16;   // close enough
+pub unsafe fn _segment_try_reclaim_abandoned(heap: *mut Heap, try_all: bool, tld: *mut SegmentsTld) -> bool {
+  let count: usize = abandoned_count.load(Ordering::Relaxed);
+  let atmost: usize = if try_all {
+    count + 16  // close enough
   }
   else {
-    atmost = abandoned_count/8;    // at most 1/8th of all outstanding (estimated)
-    if atmost < 8 {atmost = 8};    // but at least 8
-  }
+    core::cmp::max(count / 8, 8)  // at most 1/8th of all outstanding (estimated) but at least 8
+  };
 
   // for `atmost` `reclaimed` abandoned segments...
-  while(atmost > reclaimed) {
+  let mut reclaimed: usize = 0;
+  lock_acquire(&(*tld).lock);
+  while atmost > reclaimed {
     // try to claim the head of the abandoned segments
-    segment: *mut Segment;
-    do {
-      segment = (abandoned: *mut Segment;
-    } while(segment.is_not_null() && !atomic_compare_exchange_ptr((volatile void**)&abandoned, (*segment).abandoned_next, segment));
-    if segment.is_null() {break}; // stop early if no more segments available
+    let segment: *mut Segment = abandoned_pop();
+    if segment.is_null() { break; } // stop early if no more segments available
 
     // got it.
-    atomic_decrement(&abandoned_count);
-    (*segment).thread_id = _thread_id();
-    (*segment).abandoned_next = null_mut();
-    segments_track_size((long)(*segment).segment_size,tld);
+    (*segment).thread_id = thread_id();
+    (*segment).purge_expire = 0;
+    (*tld).current_size += (*segment).segment_size;
+    if (*tld).current_size > (*tld).peak_size { (*tld).peak_size = (*tld).current_size; }
     debug_assert!((*segment).next.is_null() && (*segment).prev.is_null());
     assert!(segment_is_valid(segment));
-    stat_decrease(*((*tld).stats).segments_abandoned,1);
+    _stat_decrease(&mut (*(*tld).stats).segments_abandoned, 1);
     // add its free pages to the the current thread
-    if ((*segment).page_kind == PAGE_SMALL && segment_has_free(segment)) {
-      segment_enqueue(&(*tld).small_free, segment);
+    if (*segment).page_kind == PAGE_SMALL && segment_has_free(segment) {
+      segment_enqueue(&mut (*tld).small_free, segment);
     }
     // add its abandoned pages to the current thread
-    assert((*segment).abandoned == (*segment).used);
-    for (size_t i = 0; i < (*segment).capacity; i++) {
-      page: *mut page = &(*segment).pages[i];
-      if ((*page).segment_in_use) {
-        (*segment).abandoned--;
-        assert((*page).next.is_null());
-        stat_decrease( (*(*tld).stats).pages_abandoned, 1);
-        if (page_all_free(page)) {
+    debug_assert!((*segment).abandoned == (*segment).used);
+    for i in 0..(*segment).capacity {
+      let page: *mut Page = (*segment).pages.add(i);
+      if (*page).segment_in_use {
+        (*segment).abandoned -= 1;
+        debug_assert!((*page).next.is_null());
+        _stat_decrease(&mut (*(*tld).stats).pages_abandoned, 1);
+        if page_all_free(page) {
           // if everything free by now, free the page
-          segment_page_clear(segment,page,(*tld).stats);
+          segment_page_clear(segment, page, tld);
         }
         else {
           // otherwise reclaim it
-          _page_reclaim(heap,page);
+          _page_reclaim(heap, page);
         }
       }
     }
-    assert((*segment).abandoned == 0);
-    if ((*segment).used == 0) {  // due to page_clear
-      segment_free(segment,false,tld);
+    debug_assert!((*segment).abandoned == 0);
+    if (*segment).used == 0 {  // due to page_clear
+      segment_free(segment, false, tld);
     }
     else {
-      reclaimed++;
+      reclaimed += 1;
     }
   }
-  return (reclaimed>0);
+  lock_release(&(*tld).lock);
+  reclaimed > 0
 }
 
 
@@ -624,6 +1365,8 @@ unsafe fn _segment_try_reclaim_abandoned( heap: *mut heap, try_all: bool, tld: *
 unsafe fn segment_small_page_alloc_in(segment: *mut Segment, tld: *mut SegmentsTld) -> *mut page {
   debug_assert!(segment_has_free(segment));
   page: *mut page = segment_find_free(segment);
+  segment_page_reset_remove(page, tld);  // reused before its reset delay expired
+  if !segment_page_commit(segment, page, (*tld).stats) { return null_mut(); }
   (*page).segment_in_use = true;
   (*segment).used += 1;
   debug_assert!((*segment).used <= (*segment).capacity);
@@ -654,8 +1397,12 @@ unsafe fn segment_small_page_alloc(tld: *mut SegmentsTld, os_tld: *mut OsTld) ->
 unsafe fn segment_large_page_alloc(tld: *mut SegmentsTld, os_tld: *mut OsTld) -> *mut page {
   segment: *mut Segment = segment_alloc(0,PAGE_LARGE,LARGE_PAGE_SHIFT,tld,os_tld);
   if segment.is_null() {return null_mut()};
-  (*segment).used = 1;
   page: *mut page = &(*segment).pages[0];
+  if !segment_page_commit(segment, page, (*tld).stats) {
+    segment_free(segment, false, tld);
+    return null_mut();
+  }
+  (*segment).used = 1;
   (*page).segment_in_use = true;
   return page;
 }
@@ -670,11 +1417,88 @@ unsafe fn segment_huge_page_alloc(size: usize, tld: *mut SegmentsTld, os_tld: *m
     return page;
 }
 
+// Resize the segment of a huge page to hold `size` bytes by remapping it.
+// The segment may move but stays `MI_SEGMENT_SIZE` aligned. Returns the page at its
+// (possibly new) address, or null if remapping failed; the old page is then still valid.
+pub unsafe fn _segment_huge_page_realloc(page: *mut Page, size: usize, tld: *mut SegmentsTld) -> *mut Page {
+    let mut segment: *mut Segment = page_segment(page);
+    debug_assert!((*segment).page_kind == PAGE_HUGE);
+    assert!(segment_is_valid(segment));
+    // guard pages would end up in the middle of the remapped segment
+    if option_is_enabled(option_secure) { return null_mut(); }
+
+    let mut pre_size: usize = 0;
+    let mut info_size: usize = 0;
+    let new_size: usize = segment_size(1, size, &mut pre_size, &mut info_size);
+    let old_size: usize = (*segment).segment_size;
+    debug_assert!(pre_size == (*segment).segment_info_size);
+    if new_size != old_size {
+        let old_start: *mut u8 = segment_start(segment);
+        let p: *mut u8 = _os_remap(old_start, old_size, new_size, MI_SEGMENT_SIZE, (*tld).stats);
+        if p.is_null() { return null_mut(); }
+        segment_map_set(old_start, false);
+        segment_map_set(p, true);
+        #[cfg(not(feature = "oob-meta"))]
+        {
+          let pages_offset: usize = (*segment).pages as usize - segment as usize;
+          segment = p as *mut Segment;
+          (*segment).pages = p.add(pages_offset) as *mut Page;
+          (*segment).cookie = ptr_cookie(segment as *const u8);
+        }
+        #[cfg(feature = "oob-meta")]
+        {
+          // only the memory moved; the header stays and is registered at the new address
+          // (clear the old entry first: it is the same entry when remapped in place)
+          if p != old_start { segment_meta_set(old_start, null_mut(), (*tld).stats); }
+          if !segment_meta_set(p, segment, (*tld).stats) { _error_message(libc::ENOMEM, format_args!("unable to register remapped segment {:p}", p)); }
+          (*segment).start = p;
+        }
+        (*segment).segment_size = new_size;
+        (*segment).commit_mask = commit_mask_full(new_size);  // huge segments are fully committed
+        segments_track_resize(old_size, new_size, tld);
+    }
+
+    let page: *mut Page = (*segment).pages;
+    _stat_update(&mut (*(*tld).stats).huge, size as i64 - (*page).block_size as i64);
+    (*page).block_size = size;
+    debug_assert!((*segment).segment_size - (*segment).segment_info_size >= size);
+    return page;
+}
+
+// Grow the segment of a huge page in place so the page holds `size` bytes.
+// Returns `false` if the adjoining virtual range could not be used.
+pub unsafe fn _segment_huge_page_expand(page: *mut Page, size: usize, tld: *mut SegmentsTld) -> bool {
+    let segment: *mut Segment = page_segment(page);
+    debug_assert!((*segment).page_kind == PAGE_HUGE);
+    assert!(segment_is_valid(segment));
+    // the guard page at the end would end up inside the page
+    if option_is_enabled(option_secure) { return false; }
+
+    let mut pre_size: usize = 0;
+    let mut info_size: usize = 0;
+    let new_size: usize = segment_size(1, size, &mut pre_size, &mut info_size);
+    let old_size: usize = (*segment).segment_size;
+    if new_size > old_size {
+        if !_os_expand(segment_start(segment), old_size, new_size, (*tld).stats) { return false; }
+        (*segment).segment_size = new_size;
+        (*segment).commit_mask = commit_mask_full(new_size);  // huge segments are fully committed
+        segments_track_resize(old_size, new_size, tld);
+    }
+    if size > (*page).block_size {
+        _stat_increase(&mut (*(*tld).stats).huge, (size - (*page).block_size) as _);
+        (*page).block_size = size;
+    }
+    debug_assert!((*segment).segment_size - (*segment).segment_info_size >= size);
+    return true;
+}
+
 /* -----------------------------------------------------------
    Page allocation and free
 ----------------------------------------------------------- */
 
-unsafe fn _segment_page_alloc(block_size: usize, tld: *mut SegmentsTld, os_tld: *mut OsTld) -> *mut page {
+pub unsafe fn _segment_page_alloc(block_size: usize, tld: *mut SegmentsTld, os_tld: *mut OsTld) -> *mut page {
+    lock_acquire(&(*tld).lock);
+    if option_get(option_reset_delay) > 0 { segment_delayed_purge(tld, false); }
     let mut page: *mut page;
     if (block_size < SMALL_PAGE_SIZE / 8) {
         // smaller blocks than 8kb (assuming SMALL_PAGE_SIZE == 64kb)
@@ -685,5 +1509,19 @@ unsafe fn _segment_page_alloc(block_size: usize, tld: *mut SegmentsTld, os_tld:
         page = segment_huge_page_alloc(block_size,tld,os_tld);
     }
     assert!(page.is_null() || segment_is_valid(_page_segment(page)));
+    lock_release(&(*tld).lock);
     return page;
 }
+
+// Allocate a page for a single guarded block of `block_size` bytes (see `alloc.rs`).
+// It always gets a huge segment of its own so its guard page can be protected
+// without affecting other pages.
+pub unsafe fn _segment_guarded_page_alloc(block_size: usize, tld: *mut SegmentsTld, os_tld: *mut OsTld) -> *mut Page {
+    lock_acquire(&(*tld).lock);
+    let page: *mut Page = segment_huge_page_alloc(block_size, tld, os_tld);
+    if !page.is_null() {
+        (*page_segment(page)).is_guarded = true;
+    }
+    lock_release(&(*tld).lock);
+    page
+}
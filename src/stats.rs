@@ -1,5 +1,18 @@
-use crate::types::*;
-use core::sync::atomic::Ordering;
+use crate::{init::*, internal::*, types::*};
+use core::{ptr::addr_of_mut, sync::atomic::Ordering};
+
+// Monotonic clock in milliseconds
+#[cfg(not(windows))]
+pub fn _clock_now() -> i64 {
+    let mut t = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut t); }
+    (t.tv_sec as i64 * 1000) + (t.tv_nsec as i64 / 1_000_000)
+}
+
+#[cfg(windows)]
+pub fn _clock_now() -> i64 {
+    unsafe { winapi::um::sysinfoapi::GetTickCount64() as i64 }
+}
 
 pub unsafe fn _stat_increase(stat: *mut StatCount, amount: i64) {
     _stat_update(stat, amount);
@@ -24,4 +37,66 @@ pub unsafe fn _stat_update(stat: *mut StatCount, amount: i64) {
     } else {
         (*stat).freed.fetch_add(-amount, Ordering::Relaxed);
     }
-}
\ No newline at end of file
+}
+
+pub unsafe fn _stat_counter_increase(stat: *mut StatCounter, amount: i64) {
+    (*stat).count.fetch_add(1, Ordering::Relaxed);
+    (*stat).total.fetch_add(amount, Ordering::Relaxed);
+}
+
+// Reset the statistics of the process and of the current thread
+pub unsafe fn stats_reset() {
+    let heap: *mut Heap = get_default_heap();
+    if heap_is_initialized(heap) && addr_of_mut!((*(*heap).tld).stats) != addr_of_mut!(stats_main) {
+        addr_of_mut!((*(*heap).tld).stats).write(Stats::new());
+    }
+    addr_of_mut!(stats_main).write(Stats::new());
+}
+
+// Add the counts of `src` to `stat` and clear them in `src`
+unsafe fn stat_add(stat: *mut StatCount, src: *mut StatCount) {
+    if stat == src { return; }
+    (*stat).allocated.fetch_add((*src).allocated.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+    (*stat).freed.fetch_add((*src).freed.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+    (*stat).current.fetch_add((*src).current.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+    // peak is not quite right when merging, but it is only an estimate anyway
+    (*stat).peak.fetch_add((*src).peak.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+}
+
+unsafe fn stat_counter_add(stat: *mut StatCounter, src: *mut StatCounter) {
+    if stat == src { return; }
+    (*stat).total.fetch_add((*src).total.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+    (*stat).count.fetch_add((*src).count.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+}
+
+// Merge the statistics of an exiting thread into the process statistics
+pub unsafe fn stats_done(stats: *mut Stats) {
+    let main: *mut Stats = addr_of_mut!(stats_main);
+    if stats == main { return; }
+    stat_add(&mut (*main).segments, &mut (*stats).segments);
+    stat_add(&mut (*main).pages, &mut (*stats).pages);
+    stat_add(&mut (*main).reserved, &mut (*stats).reserved);
+    stat_add(&mut (*main).committed, &mut (*stats).committed);
+    stat_add(&mut (*main).reset, &mut (*stats).reset);
+    stat_add(&mut (*main).page_committed, &mut (*stats).page_committed);
+    stat_add(&mut (*main).segments_abandoned, &mut (*stats).segments_abandoned);
+    stat_add(&mut (*main).pages_abandoned, &mut (*stats).pages_abandoned);
+    stat_add(&mut (*main).pages_extended, &mut (*stats).pages_extended);
+    stat_add(&mut (*main).mmap_calls, &mut (*stats).mmap_calls);
+    stat_add(&mut (*main).mmap_right_align, &mut (*stats).mmap_right_align);
+    stat_add(&mut (*main).mmap_ensure_aligned, &mut (*stats).mmap_ensure_aligned);
+    stat_add(&mut (*main).commit_calls, &mut (*stats).commit_calls);
+    stat_add(&mut (*main).threads, &mut (*stats).threads);
+    stat_add(&mut (*main).huge, &mut (*stats).huge);
+    stat_add(&mut (*main).malloc, &mut (*stats).malloc);
+    stat_add(&mut (*main).guarded, &mut (*stats).guarded);
+    stat_counter_add(&mut (*main).searches, &mut (*stats).searches);
+    stat_counter_add(&mut (*main).segment_cache_hits, &mut (*stats).segment_cache_hits);
+    stat_counter_add(&mut (*main).segment_cache_misses, &mut (*stats).segment_cache_misses);
+    stat_counter_add(&mut (*main).segment_cache_shrinks, &mut (*stats).segment_cache_shrinks);
+    stat_counter_add(&mut (*main).segment_cache_evictions, &mut (*stats).segment_cache_evictions);
+    #[cfg(stats)]
+    for i in 0..=MI_BIN_HUGE {
+        stat_add(&mut (*main).normal[i], &mut (*stats).normal[i]);
+    }
+}
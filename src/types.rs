@@ -1,6 +1,7 @@
 use core::{
     mem::size_of,
-    sync::atomic::{AtomicI64, AtomicPtr, AtomicUsize},
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicPtr, AtomicUsize, Ordering},
 };
 
 #[cfg(target_pointer_width = "64")]
@@ -31,6 +32,9 @@ pub const MI_LARGE_PAGE_SIZE: usize =         1 << MI_LARGE_PAGE_SHIFT;
 pub const MI_SMALL_PAGES_PER_SEGMENT: usize = MI_SEGMENT_SIZE / MI_SMALL_PAGE_SIZE;
 pub const MI_LARGE_PAGES_PER_SEGMENT: usize = MI_SEGMENT_SIZE / MI_LARGE_PAGE_SIZE;
 
+// Segment memory is committed on demand in chunks of this size (one bit each in `commit_mask`)
+pub const MI_COMMIT_SIZE: usize =             MI_SEGMENT_SIZE / 64;      // 64kb
+
 pub const MI_LARGE_SIZE_MAX: usize =          MI_LARGE_PAGE_SIZE / 8;   // 512kb on 64-bit
 pub const MI_LARGE_WSIZE_MAX: usize =         MI_LARGE_SIZE_MAX >> MI_INTPTR_SHIFT;
 
@@ -46,6 +50,7 @@ pub const MI_BIN_FULL: usize = MI_BIN_HUGE + 1;
 // Minimal alignment necessary. On most platforms 16 bytes are needed
 // due to SSE registers for example. This must be at least `MI_INTPTR_SIZE`
 pub const MI_MAX_ALIGN_SIZE: usize = 16;   // sizeof(max_align_t)
+pub const MI_GUARDED_DELAY_MAX: usize = 16; // freed guarded blocks a heap keeps protected
 
 pub struct Block {
     pub next: usize,
@@ -72,7 +77,7 @@ pub struct PageFlagsInner {
 
 // Thread free list.
 // We use bottom 2 bits of the pointer for the `use_delayed_free` and `delayed_freeing` flags.
-pub struct ThreadFree { value: AtomicUsize, }
+pub struct ThreadFree { pub value: AtomicUsize, }
 
 pub struct Page {
     // "owned" by the segment
@@ -86,7 +91,8 @@ pub struct Page {
     pub reserved: u16,                             // numbes of blocks reserved in memory
 
     pub free: *mut Block,                          // list of available free blocks (`malloc` allocates from this list)
-    pub cookie: usize,                             // random cookie to encode the free lists
+    #[cfg(feature = "secure")]
+    pub keys: [usize; 2],                          // two random keys to encode the free lists
     pub used: usize,                               // number of blocks in use (including blocks in `local_free` and `thread_free`)
 
     pub local_free: *mut Block,                    // list of deferred free blocks by this thread (migrates to `free`)
@@ -98,6 +104,9 @@ pub struct Page {
     pub heap: *mut Heap,                           // the owning heap
     pub next: *mut Page,                           // next page owned by this thread with the same `block_size`
     pub prev: *mut Page,                           // previous page owned by this thread with the same `block_size`
+    pub reset_expire: i64,                         // time at which a freed page is reset (`0` if not in the `pages_reset` queue)
+    #[cfg(feature = "secure")]
+    pub quarantined: u16,                          // number of blocks of this page in the heap quarantine (counted in `used`)
 }
 
 #[derive(PartialEq)]
@@ -122,6 +131,13 @@ pub struct Segment {
     pub segment_size: usize,// for huge pages this may be different from `MI_SEGMENT_SIZE`
     pub segment_info_size: usize,  // space we are using from the first page for segment meta-data and possible guard pages.
     pub cookie: usize,      // verify addresses in debug mode: `mi_ptr_cookie(segment) == segment->cookie`
+    pub purge_expire: i64,  // time at which the unused memory of a cached or abandoned segment is purged (`0` if not scheduled)
+    pub cache_time: i64,    // time at which the segment was put in a segment cache
+    pub commit_mask: u64,   // bit `i` is set if the `i`th `MI_COMMIT_SIZE` chunk is committed (segments larger than `MI_SEGMENT_SIZE` are always fully committed)
+    pub is_guarded: bool,   // `true` if the segment holds a single sampled allocation in front of a guard page
+    pub guarded_size: usize,// size handed out of that allocation, which ends right at the guard page
+    #[cfg(feature = "oob-meta")]
+    pub start: *mut u8,     // the segment memory; the segment info itself is allocated apart from it
 
     // layout like this to optimize access in `mi_free`
     pub page_shift: usize,  // `1 << page_shift` == the page sizes == `page->block_size * page->reserved` (unless the first page, then `-segment_info_size`).
@@ -137,6 +153,16 @@ pub struct PageQueue {
     pub block_size: usize,
 }
 
+// Freed blocks held back from reuse, oldest first.
+// The blocks are linked through their `next` field, encoded with the keys of their own page.
+#[cfg(feature = "secure")]
+pub struct Quarantine {
+    pub first: *mut Block,
+    pub last: *mut Block,
+    pub count: usize,   // number of blocks in the quarantine
+    pub size: usize,    // total block size of the blocks in the quarantine
+}
+
 // A heap owns a set of pages.
 pub struct Heap {
     pub tld:                  *mut Tld,
@@ -148,6 +174,11 @@ pub struct Heap {
     pub random:               usize,                                 // random number used for secure allocation
     pub page_count:           usize,                                 // total number of pages in the `pages` queues.
     pub no_reclaim:           bool,                                  // `true` if this heap should not reclaim abandoned pages
+    pub guarded_sample_count: usize,                                 // allocations since the last guarded one
+    pub guarded_delayed:      [*mut Block; MI_GUARDED_DELAY_MAX],    // freed guarded blocks of this heap that are still protected
+    pub guarded_delayed_next: usize,                                 // slot of the oldest of those
+    #[cfg(feature = "secure")]
+    pub quarantine:           Quarantine,                            // locally freed blocks waiting to go back to their page
 }
 
 // ------------------------------------------------------
@@ -183,7 +214,12 @@ pub struct Stats {
     pub threads: StatCount,
     pub huge: StatCount,
     pub malloc: StatCount,
+    pub guarded: StatCount,                   // sampled allocations placed in front of a guard page
     pub searches: StatCounter,
+    pub segment_cache_hits: StatCounter,      // segments taken from a segment cache (total is in bytes)
+    pub segment_cache_misses: StatCounter,    // segments that had to be allocated from the OS
+    pub segment_cache_shrinks: StatCounter,   // cached segments shrunk to fit a request
+    pub segment_cache_evictions: StatCounter, // cached segments released to make room
     #[cfg(stats)]
     pub normal: [StatCount; MI_BIN_HUGE + 1],
 }
@@ -206,7 +242,9 @@ pub struct SegmentsTld {
     pub cache_count:   usize,         // number of segments in the cache
     pub cache_size:    usize,         // total size of all segments in the cache
     pub cache:         SegmentQueue,  // (small) cache of segments for small and large pages (to avoid repeated mmap calls)
+    pub pages_reset:   PageQueue,     // freed pages waiting for the reset delay to expire (in order of expiration)
     pub stats:         *mut Stats,    // points to tld stats
+    pub lock:          AtomicBool,    // held while the segments are modified, as the purge thread may visit them
 }
 
 // OS thread local data
@@ -225,4 +263,197 @@ pub struct Tld {
     pub segments:      SegmentsTld,  // segment tld
     pub os:            OsTld,        // os tld
     pub stats:         Stats,        // statistics
-}
\ No newline at end of file
+    pub next:          *mut Tld,     // list of the thread local data of all threads
+    pub prev:          *mut Tld,
+}
+// ------------------------------------------------------
+// Constant initializers
+// These set up the statically allocated main heap
+// without doing any allocation.
+// ------------------------------------------------------
+
+impl ThreadFree {
+    pub const fn new() -> ThreadFree {
+        ThreadFree { value: AtomicUsize::new(0) }
+    }
+
+    pub fn head(&self) -> *mut Block {
+        (self.value.load(Ordering::Relaxed) & !3) as *mut Block
+    }
+}
+
+impl PageFlags {
+    pub fn has_aligned(&self) -> bool {
+        unsafe { self.inner.has_aligned }
+    }
+
+    pub fn is_full(&self) -> bool {
+        unsafe { self.inner.is_full }
+    }
+
+    pub fn set_has_aligned(&mut self, has_aligned: bool) {
+        unsafe { self.inner.has_aligned = has_aligned; }
+    }
+}
+
+impl Page {
+    pub const fn new() -> Page {
+        Page {
+            segment_idx: 0,
+            segment_in_use: false,
+            is_reset: false,
+            flags: PageFlags { value: 0 },
+            capacity: 0,
+            reserved: 0,
+            free: null_mut(),
+            #[cfg(feature = "secure")]
+            keys: [0; 2],
+            used: 0,
+            local_free: null_mut(),
+            thread_freed: AtomicUsize::new(0),
+            thread_free: ThreadFree::new(),
+            block_size: 0,
+            heap: null_mut(),
+            next: null_mut(),
+            prev: null_mut(),
+            reset_expire: 0,
+            #[cfg(feature = "secure")]
+            quarantined: 0,
+        }
+    }
+}
+
+impl PageQueue {
+    pub const fn new(block_size: usize) -> PageQueue {
+        PageQueue { first: null_mut(), last: null_mut(), block_size }
+    }
+}
+
+// Block size (in words) of the page queue of each bin, ending with the huge and full queues.
+const PAGE_QUEUE_WSIZES: [usize; MI_BIN_FULL + 1] = [
+    1,
+    1, 2, 3, 4, 5, 6, 7, 8,
+    10, 12, 14, 16, 20, 24, 28, 32,
+    40, 48, 56, 64, 80, 96, 112, 128,
+    160, 192, 224, 256, 320, 384, 448, 512,
+    640, 768, 896, 1024, 1280, 1536, 1792, 2048,
+    2560, 3072, 3584, 4096, 5120, 6144, 7168, 8192,
+    10240, 12288, 14336, 16384, 20480, 24576, 28672, 32768,
+    40960, 49152, 57344, 65536, 81920, 98304, 114688,
+    MI_LARGE_WSIZE_MAX + 1,  // 131072, Huge queue
+    MI_LARGE_WSIZE_MAX + 2,  // Full queue
+];
+
+impl Heap {
+    // `page_empty` is put in `pages_free_direct` so the allocation fast path never sees null.
+    pub const fn new(tld: *mut Tld, page_empty: *mut Page) -> Heap {
+        const EMPTY_QUEUE: PageQueue = PageQueue::new(0);
+        let mut pages = [EMPTY_QUEUE; MI_BIN_FULL + 1];
+        let mut i = 0;
+        while i < pages.len() {
+            pages[i].block_size = PAGE_QUEUE_WSIZES[i] * size_of::<usize>();
+            i += 1;
+        }
+        Heap {
+            tld,
+            pages_free_direct: [page_empty; MI_SMALL_WSIZE_MAX + 2],
+            pages,
+            thread_delayed_free: AtomicPtr::new(null_mut()),
+            thread_id: 0,
+            cookie: 0,
+            random: 0,
+            page_count: 0,
+            no_reclaim: false,
+            guarded_sample_count: 0,
+            guarded_delayed: [null_mut(); MI_GUARDED_DELAY_MAX],
+            guarded_delayed_next: 0,
+            #[cfg(feature = "secure")]
+            quarantine: Quarantine { first: null_mut(), last: null_mut(), count: 0, size: 0 },
+        }
+    }
+}
+
+impl StatCount {
+    pub const fn new() -> StatCount {
+        StatCount {
+            allocated: AtomicI64::new(0),
+            freed: AtomicI64::new(0),
+            peak: AtomicI64::new(0),
+            current: AtomicI64::new(0),
+        }
+    }
+}
+
+impl StatCounter {
+    pub const fn new() -> StatCounter {
+        StatCounter { total: AtomicI64::new(0), count: AtomicI64::new(0) }
+    }
+}
+
+impl Stats {
+    pub const fn new() -> Stats {
+        Stats {
+            segments: StatCount::new(),
+            pages: StatCount::new(),
+            reserved: StatCount::new(),
+            committed: StatCount::new(),
+            reset: StatCount::new(),
+            page_committed: StatCount::new(),
+            segments_abandoned: StatCount::new(),
+            pages_abandoned: StatCount::new(),
+            pages_extended: StatCount::new(),
+            mmap_calls: StatCount::new(),
+            mmap_right_align: StatCount::new(),
+            mmap_ensure_aligned: StatCount::new(),
+            commit_calls: StatCount::new(),
+            threads: StatCount::new(),
+            huge: StatCount::new(),
+            malloc: StatCount::new(),
+            guarded: StatCount::new(),
+            searches: StatCounter::new(),
+            segment_cache_hits: StatCounter::new(),
+            segment_cache_misses: StatCounter::new(),
+            segment_cache_shrinks: StatCounter::new(),
+            segment_cache_evictions: StatCounter::new(),
+            #[cfg(stats)]
+            normal: [const { StatCount::new() }; MI_BIN_HUGE + 1],
+        }
+    }
+}
+
+impl SegmentQueue {
+    pub const fn new() -> SegmentQueue {
+        SegmentQueue { first: null_mut(), last: null_mut() }
+    }
+}
+
+impl Tld {
+    // `stats` must point to the `stats` field of the new `Tld` itself.
+    pub const fn new(heap_backing: *mut Heap, stats: *mut Stats) -> Tld {
+        Tld {
+            heartbeat: 0,
+            heap_backing,
+            segments: SegmentsTld {
+                small_free: SegmentQueue::new(),
+                current_size: 0,
+                peak_size: 0,
+                cache_count: 0,
+                cache_size: 0,
+                cache: SegmentQueue::new(),
+                pages_reset: PageQueue::new(0),
+                stats,
+                lock: AtomicBool::new(false),
+            },
+            os: OsTld {
+                mmap_next_probable: 0,
+                mmap_previous: null_mut(),
+                pool: null_mut(),
+                pool_available: 0,
+                stats,
+            },
+            stats: Stats::new(),
+            next: null_mut(),
+            prev: null_mut(),
+        }
+    }
+}
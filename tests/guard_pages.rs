@@ -0,0 +1,44 @@
+// With `option_secure` above 1 every page of a segment ends in a guard page,
+// so writing past the end of a block runs into it before reaching another page.
+// The option is read from `MIMALLOC_SECURE` when the allocator first needs it.
+//
+// This test has not run yet: the crate does not build while parts of the
+// port are unfinished, so it is ignored until it does.
+#![cfg(unix)]
+
+use std::{env, os::unix::process::ExitStatusExt, process::Command, ptr};
+
+#[global_allocator]
+static GLOBAL: mimalloc_rs::Mimalloc = mimalloc_rs::Mimalloc;
+
+const CHILD: &str = "MIMALLOC_TEST_OVERFLOW";
+
+// Larger than a small page (64kb), so the writes cross its end
+const OVERFLOW: usize = 1 << 20;
+
+fn overflow() {
+    let block: Box<[u8; 8]> = Box::new([0; 8]);
+    let p = Box::into_raw(block) as *mut u8;
+    for i in 0..OVERFLOW {
+        unsafe { ptr::write_volatile(p.add(i), 0xAA); }
+    }
+}
+
+#[test]
+#[ignore = "not run yet: the crate does not build"]
+fn overflow_past_page_faults() {
+    if env::var_os(CHILD).is_some() {
+        overflow();
+        // only reached if no guard page was hit
+        std::process::exit(0);
+    }
+
+    // options are read once, so run this test again in a child process
+    let status = Command::new(env::current_exe().unwrap())
+        .args(&["overflow_past_page_faults", "--exact", "--ignored", "--test-threads=1", "--nocapture"])
+        .env(CHILD, "1")
+        .env("MIMALLOC_SECURE", "2")
+        .status()
+        .unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGSEGV), "child exited with {:?}", status);
+}